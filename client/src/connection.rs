@@ -21,6 +21,8 @@ pub struct Connection {
 
 	pub packet_queue: Vec<Packet>,
 	pub event_queue: Vec<ConnectionEvent>,
+
+	reconnect: bool,
 }
 
 impl Connection {
@@ -31,6 +33,8 @@ impl Connection {
 
 			packet_queue: Vec::new(),
 			event_queue: Vec::new(),
+
+			reconnect: true,
 		});
 
 		unsafe {
@@ -107,17 +111,21 @@ impl Connection {
 		}
 	}
 
+	// Once the connection drops, it stays dropped
+	pub fn stop_reconnecting(&mut self) {
+		self.reconnect = false;
+	}
+
 	pub fn send(&mut self, p: &Packet) -> bool {
 		use std::io::Write;
 
 		if self.stream.is_none() { return false }
 		if !p.is_valid_from_client() { return false }
 
-		let mut buf = [0u8; 1<<10];
-
-		let len = p.write(&mut buf);
+		let mut buf = Vec::new();
+		p.write(&mut buf);
 
-		if let Err(e) = self.stream.as_mut().unwrap().write_all(&mut buf[..len]) {
+		if let Err(e) = self.stream.as_mut().unwrap().write_all(&buf) {
 			println!("send failed {}", e);
 			return false
 		}
@@ -168,7 +176,9 @@ extern fn on_close(_: i32, vctx: *mut u8) {
 	ctx.stream = None;
 	ctx.socket_fd = -1;
 
-	unsafe{ ems::emscripten_async_call(on_retry, vctx, 1500) };
+	if ctx.reconnect {
+		unsafe{ ems::emscripten_async_call(on_retry, vctx, 1500) };
+	}
 }
 
 extern fn on_message(_: i32, ctx: *mut u8) {
@@ -190,7 +200,8 @@ extern fn on_message(_: i32, ctx: *mut u8) {
 		}
 	};
 
-	if let Some(packet) = Packet::parse(&buf[..len]) {
-		ctx.packet_queue.push(packet);
+	match Packet::parse_message(&buf[..len]) {
+		Ok(packets) => ctx.packet_queue.extend(packets),
+		Err(e) => println!("Invalid packet from server: {}", e),
 	}
 }
\ No newline at end of file
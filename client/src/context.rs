@@ -90,7 +90,7 @@ impl MainContext {
 
 	pub fn on_connect(&mut self) {
 		println!("Connected...");
-		self.connection.send(&Packet::AttemptAuthSession(123));
+		self.connection.send(&Packet::Hello(PROTOCOL_VERSION, String::from(env!("CARGO_PKG_VERSION"))));
 	}
 	
 	pub fn on_disconnect(&mut self) {
@@ -266,6 +266,20 @@ impl MainContext {
 
 		for packet in self.connection.packet_queue.clone() {
 			match packet {
+				Packet::ServerHello(version) => {
+					println!("Server speaks protocol version {}", version);
+					self.connection.send(&Packet::AttemptAuthSession(123));
+				}
+
+				// This build is out of date, and reconnecting won't fix that
+				Packet::VersionMismatch(version) => {
+					println!("Server speaks protocol version {}, this client speaks {}", version, PROTOCOL_VERSION);
+					self.connection.stop_reconnecting();
+
+					use ems;
+					ems::show_message("The server has been updated. Please reload the page.");
+				}
+
 				Packet::AuthSuccessful(token) => {
 					println!("Auth success: {}", token);
 					
@@ -203,6 +203,12 @@ pub fn start(ctx_ptr: *mut MainContext) {
 	}
 }
 
+// Shows a message over the canvas, for things the player needs to act on
+pub fn show_message(msg: &str) {
+	js! { (msg.as_ptr(), msg.len() as i32)
+		b"var el = document.getElementById('message'); el.textContent = Pointer_stringify($0, $1); el.style.display = 'block'\0" };
+}
+
 pub fn activate_pointer_lock() {
 	unsafe {
 		emscripten_request_pointerlock(ptr::null(), 1);
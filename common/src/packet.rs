@@ -1,85 +1,112 @@
-use std;
-use ::*;
-
-#[derive(Clone)]
-pub enum Packet {
-	// Client -> Server
-	Debug(String),
-	RequestNewSession,
-	AttemptAuthSession(u32),
-	RequestDownloadWorld,
-
-	// Server -> Client
-	AuthSuccessful(u32),
-	AuthFail,
-	NewSession(u32),
-}
+use std::fmt;
+use wire::{self, WireError, Encode, Decode};
 
-impl Packet {
-	pub fn get_type(&self) -> u8 {
-		match *self {
-			// Client -> Server
-			Packet::Debug(_) => 0x0,
-			Packet::RequestNewSession => 0x1,
-			Packet::AttemptAuthSession(_) => 0x2,
-			Packet::RequestDownloadWorld => 0x3,
-
-			// Server -> Client
-			Packet::AuthSuccessful(_) => 0x80,
-			Packet::AuthFail => 0x81,
-			Packet::NewSession(_) => 0x82,
+// Generates the packet enum from a list of `opcode => Variant(field: Type, ..)`,
+// along with its opcode table and the code to parse and write it.
+// Fields go on the wire in order, using their Encode and Decode impls
+macro_rules! define_packets {
+	(
+		$(#[$attr:meta])*
+		pub enum $packet:ident {
+			$( $opcode:tt => $variant:ident $(( $($field:ident: $ty:ty),+ ))* ),* $(,)*
+		}
+	) => {
+		$(#[$attr])*
+		pub enum $packet {
+			$( $variant $(( $($ty),+ ))* ),*
 		}
-	}
 
-	pub fn parse(src: &[u8]) -> Option<Packet> {
-		let ty = src[0];
+		impl $packet {
+			pub fn get_type(&self) -> u8 {
+				match *self {
+					$( $packet::$variant {..} => $opcode ),*
+				}
+			}
 
-		match ty {
-			0x0  => std::str::from_utf8(&src[1..]).ok().map(|s| Packet::Debug(String::from(s))),
-			0x1  => Some(Packet::RequestNewSession),
-			0x2  => Some(Packet::AttemptAuthSession(read_u32_from_slice(&src[1..]))),
-			0x3  => Some(Packet::RequestDownloadWorld),
+			pub fn parse(src: &[u8]) -> Result<$packet, PacketError> {
+				let mut reader = wire::Reader::new(src);
+				let ty = reader.read_u8().map_err(|_| PacketError::Empty)?;
+				let wire_error = |e| PacketError::from_wire(ty, e);
 
-			0x80 => Some(Packet::AuthSuccessful(read_u32_from_slice(&src[1..]))),
-			0x81 => Some(Packet::AuthFail),
-			0x82 => Some(Packet::NewSession(read_u32_from_slice(&src[1..]))),
+				let packet = match ty {
+					$( $opcode => $packet::$variant $(( $( <$ty as Decode>::decode(&mut reader).map_err(wire_error)? ),+ ))*, )*
+					_ => return Err(PacketError::UnknownType(ty))
+				};
 
-			_ => None
+				reader.finish().map_err(wire_error)?;
+				Ok(packet)
+			}
+
+			// Appends the encoded packet to dst
+			pub fn write(&self, dst: &mut Vec<u8>) {
+				let mut writer = wire::Writer::new(dst);
+				writer.write_u8(self.get_type());
+
+				match *self {
+					$( $packet::$variant $(( $(ref $field),+ ))* => { $($( $field.encode(&mut writer); )+)* } )*
+				}
+			}
 		}
 	}
+}
 
-	pub fn write(&self, dst: &mut [u8]) -> usize {
-		assert!(dst.len() > 64);
+// Bumped whenever packets change in a way the other end wouldn't understand.
+// Hello and the replies to it have to keep their opcodes and fields, so that any two versions can tell they disagree
+pub const PROTOCOL_VERSION: u32 = 2;
 
-		dst[0] = self.get_type();
+// Starts a message that holds several packets, each prefixed with its length, instead of just one
+pub const BATCH_TYPE: u8 = 0xFF;
 
+define_packets! {
+	#[derive(Clone)]
+	pub enum Packet {
+		// Client -> Server
+		0x0  => Debug(message: String),
+		0x1  => RequestNewSession,
+		0x2  => AttemptAuthSession(token: u32),
+		0x3  => RequestDownloadWorld,
+		0x4  => Hello(protocol_version: u32, client_build: String),
+
+		// Server -> Client
+		0x80 => AuthSuccessful(token: u32),
+		0x81 => AuthFail,
+		0x82 => NewSession(token: u32),
+		0x83 => ServerHello(protocol_version: u32),
+		0x84 => VersionMismatch(server_version: u32),
+	}
+}
+
+impl Packet {
+	// Packets that can be discarded when a client isn't keeping up, because they're
+	// either unimportant or will be superseded
+	pub fn is_droppable(&self) -> bool {
 		match *self {
-			Packet::Debug(ref s) => {
-				let len = s.len() + 1;
+			Packet::Debug(_) => true,
+			_ => false,
+		}
+	}
 
-				assert!(dst.len() >= len);
-				dst[1..len].copy_from_slice(&s.as_bytes());
+	// Parses a whole message, which is either a single packet or a batch of them
+	pub fn parse_message(src: &[u8]) -> Result<Vec<Packet>, PacketError> {
+		if src.first() != Some(&BATCH_TYPE) {
+			return Packet::parse(src).map(|p| vec![p]);
+		}
 
-				len
-			}
-			Packet::RequestNewSession => 1,
-			Packet::AttemptAuthSession(tok) => {
-				write_u32_to_slice(&mut dst[1..], tok);
-				5
-			}
+		let mut reader = wire::Reader::new(&src[1..]);
+		let mut packets = Vec::new();
 
-			Packet::RequestDownloadWorld => 1,
+		while reader.remaining() > 0 {
+			let packet = reader.read_prefixed_bytes()
+				.map_err(|e| PacketError::from_wire(BATCH_TYPE, e))?;
 
-			Packet::AuthSuccessful(tok) => {
-				write_u32_to_slice(&mut dst[1..], tok);
-				5
-			}
-			Packet::AuthFail => 1,
-			Packet::NewSession(tok) => {
-				write_u32_to_slice(&mut dst[1..], tok);
-				5
-			}
+			packets.push(Packet::parse(packet)?);
 		}
+
+		if packets.is_empty() {
+			return Err(PacketError::Empty);
+		}
+
+		Ok(packets)
 	}
 
 	pub fn is_valid_from_client(&self) -> bool {
@@ -90,3 +117,187 @@ impl Packet {
 		self.get_type() >= 0x80
 	}
 }
+
+// Collects packets to be sent as one message.
+// A lone packet is written as is, so there's no overhead when there's nothing to batch
+pub struct Batch {
+	buf: Vec<u8>,
+	count: usize,
+
+	// Where the first packet starts, after its length
+	first_start: usize,
+}
+
+impl Batch {
+	pub fn new() -> Batch {
+		Batch { buf: Vec::new(), count: 0, first_start: 0 }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	// Roughly how big the message would be
+	pub fn len(&self) -> usize {
+		self.buf.len()
+	}
+
+	pub fn push(&mut self, packet: &Packet) {
+		let mut encoded = Vec::new();
+		packet.write(&mut encoded);
+		self.push_encoded(&encoded);
+	}
+
+	// For packets that have already been written, so they only need encoding once however many batches they go in
+	pub fn push_encoded(&mut self, packet: &[u8]) {
+		let mut writer = wire::Writer::new(&mut self.buf);
+		writer.write_varint(packet.len() as u64);
+
+		if self.count == 0 {
+			self.first_start = writer.len();
+		}
+
+		writer.write_bytes(packet);
+		self.count += 1;
+	}
+
+	// Appends the message to dst, and leaves the batch empty
+	pub fn write(&mut self, dst: &mut Vec<u8>) {
+		match self.count {
+			0 => {},
+			1 => dst.extend_from_slice(&self.buf[self.first_start..]),
+			_ => {
+				dst.push(BATCH_TYPE);
+				dst.extend_from_slice(&self.buf);
+			}
+		}
+
+		self.buf.clear();
+		self.count = 0;
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+	Empty,
+	UnknownType(u8),
+	Truncated(u8),
+	TrailingBytes(u8),
+	InvalidUtf8(u8),
+	Malformed(u8),
+}
+
+impl PacketError {
+	fn from_wire(ty: u8, error: WireError) -> PacketError {
+		match error {
+			WireError::UnexpectedEnd => PacketError::Truncated(ty),
+			WireError::TrailingBytes(_) => PacketError::TrailingBytes(ty),
+			WireError::InvalidUtf8 => PacketError::InvalidUtf8(ty),
+			WireError::InvalidVarint => PacketError::Malformed(ty),
+		}
+	}
+}
+
+impl fmt::Display for PacketError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			PacketError::Empty => write!(f, "Empty packet"),
+			PacketError::UnknownType(ty) => write!(f, "Unknown packet type {:#x}", ty),
+			PacketError::Truncated(ty) => write!(f, "Packet {:#x} is truncated", ty),
+			PacketError::TrailingBytes(ty) => write!(f, "Packet {:#x} has trailing bytes", ty),
+			PacketError::InvalidUtf8(ty) => write!(f, "Packet {:#x} contains invalid utf8", ty),
+			PacketError::Malformed(ty) => write!(f, "Packet {:#x} is malformed", ty),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn packets_round_trip() {
+		let packets = vec![
+			Packet::Debug(String::from("héllo")),
+			Packet::RequestNewSession,
+			Packet::AttemptAuthSession(0xDEADBEEF),
+			Packet::RequestDownloadWorld,
+			Packet::AuthSuccessful(7),
+			Packet::AuthFail,
+			Packet::NewSession(1<<31),
+			Packet::Hello(PROTOCOL_VERSION, String::from("0.1.0")),
+			Packet::ServerHello(PROTOCOL_VERSION),
+			Packet::VersionMismatch(PROTOCOL_VERSION),
+		];
+
+		for packet in packets {
+			let mut buf = Vec::new();
+			packet.write(&mut buf);
+			assert_eq!(buf[0], packet.get_type());
+
+			let mut reencoded = Vec::new();
+			Packet::parse(&buf).unwrap().write(&mut reencoded);
+			assert_eq!(buf, reencoded);
+		}
+	}
+
+	#[test]
+	fn fields_are_little_endian() {
+		let mut buf = Vec::new();
+		Packet::AuthSuccessful(0x01020304).write(&mut buf);
+		assert_eq!(buf, [0x80, 0x04, 0x03, 0x02, 0x01]);
+
+		let mut buf = Vec::new();
+		Packet::Debug(String::from("hi")).write(&mut buf);
+		assert_eq!(buf, [0x0, 2, b'h', b'i']);
+	}
+
+	#[test]
+	fn batches_round_trip() {
+		let mut batch = Batch::new();
+		batch.push(&Packet::AuthSuccessful(5));
+
+		// One packet doesn't need a batch
+		let mut buf = Vec::new();
+		batch.write(&mut buf);
+		assert_eq!(buf, [0x80, 5, 0, 0, 0]);
+		assert!(batch.is_empty());
+
+		batch.push(&Packet::NewSession(1));
+		batch.push(&Packet::AuthFail);
+		batch.push(&Packet::Debug(String::from("hi")));
+
+		let mut buf = Vec::new();
+		batch.write(&mut buf);
+		assert_eq!(buf, [BATCH_TYPE, 5, 0x82, 1, 0, 0, 0, 1, 0x81, 4, 0x0, 2, b'h', b'i']);
+
+		let packets = Packet::parse_message(&buf).unwrap();
+		assert_eq!(packets.iter().map(|p| p.get_type()).collect::<Vec<_>>(), [0x82, 0x81, 0x0]);
+
+		let packets = Packet::parse_message(&[0x81]).unwrap();
+		assert!(packets.len() == 1 && packets[0].get_type() == 0x81);
+	}
+
+	#[test]
+	fn bad_batches_are_errors() {
+		assert_eq!(Packet::parse_message(&[BATCH_TYPE]).err(), Some(PacketError::Empty));
+		assert_eq!(Packet::parse_message(&[BATCH_TYPE, 0]).err(), Some(PacketError::Empty));
+		assert_eq!(Packet::parse_message(&[BATCH_TYPE, 5, 0x81]).err(), Some(PacketError::Truncated(BATCH_TYPE)));
+		assert_eq!(Packet::parse_message(&[BATCH_TYPE, 1, 0x81, 1, 0x7F]).err(), Some(PacketError::UnknownType(0x7F)));
+
+		// Batches don't nest
+		assert_eq!(Packet::parse_message(&[BATCH_TYPE, 2, BATCH_TYPE, 0]).err(), Some(PacketError::UnknownType(BATCH_TYPE)));
+	}
+
+	// Changing these would stop old clients from being told to reload
+	#[test]
+	fn hello_layout_is_fixed() {
+		let mut buf = Vec::new();
+		Packet::Hello(1, String::from("b")).write(&mut buf);
+		assert_eq!(buf, [0x4, 1, 0, 0, 0, 1, b'b']);
+
+		let mut buf = Vec::new();
+		Packet::VersionMismatch(1).write(&mut buf);
+		assert_eq!(buf, [0x84, 1, 0, 0, 0]);
+	}
+}
@@ -0,0 +1,423 @@
+use std::fmt;
+use std::str;
+use math::{Vec2, Vec3, Vec2i};
+
+// Everything on the wire is little endian, whatever the host is.
+// Strings and byte slices that don't run to the end of a packet are prefixed with their length as a varint
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+	UnexpectedEnd,
+	TrailingBytes(usize),
+	InvalidUtf8,
+	InvalidVarint,
+}
+
+impl fmt::Display for WireError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			WireError::UnexpectedEnd => write!(f, "Unexpected end of data"),
+			WireError::TrailingBytes(n) => write!(f, "{} unexpected trailing bytes", n),
+			WireError::InvalidUtf8 => write!(f, "Invalid utf8"),
+			WireError::InvalidVarint => write!(f, "Varint is overlong or doesn't fit in 64 bits"),
+		}
+	}
+}
+
+// Reads values off the front of a slice. Nothing is consumed by a read that fails
+pub struct Reader<'a> {
+	buf: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	pub fn new(buf: &'a [u8]) -> Self {
+		Reader { buf, pos: 0 }
+	}
+
+	pub fn remaining(&self) -> usize {
+		self.buf.len() - self.pos
+	}
+
+	// Fails if anything is left unread
+	pub fn finish(&self) -> Result<(), WireError> {
+		match self.remaining() {
+			0 => Ok(()),
+			n => Err(WireError::TrailingBytes(n)),
+		}
+	}
+
+	pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+		if len > self.remaining() {
+			return Err(WireError::UnexpectedEnd);
+		}
+
+		let bytes = &self.buf[self.pos..self.pos+len];
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	pub fn read_str(&mut self, len: usize) -> Result<&'a str, WireError> {
+		let start = self.pos;
+		let bytes = self.read_bytes(len)?;
+
+		str::from_utf8(bytes).map_err(|_| {
+			self.pos = start;
+			WireError::InvalidUtf8
+		})
+	}
+
+	fn read_le(&mut self, len: usize) -> Result<u64, WireError> {
+		let bytes = self.read_bytes(len)?;
+		Ok(bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64))
+	}
+
+	pub fn read_u8(&mut self) -> Result<u8, WireError> { self.read_le(1).map(|v| v as u8) }
+	pub fn read_u16(&mut self) -> Result<u16, WireError> { self.read_le(2).map(|v| v as u16) }
+	pub fn read_u32(&mut self) -> Result<u32, WireError> { self.read_le(4).map(|v| v as u32) }
+	pub fn read_u64(&mut self) -> Result<u64, WireError> { self.read_le(8) }
+	pub fn read_i32(&mut self) -> Result<i32, WireError> { self.read_u32().map(|v| v as i32) }
+	pub fn read_f32(&mut self) -> Result<f32, WireError> { self.read_u32().map(f32::from_bits) }
+	pub fn read_f64(&mut self) -> Result<f64, WireError> { self.read_u64().map(f64::from_bits) }
+
+	// LEB128, seven bits at a time with the high bit set on all but the last byte
+	pub fn read_varint(&mut self) -> Result<u64, WireError> {
+		let mut value = 0u64;
+
+		for i in 0..10 {
+			let byte = match self.buf.get(self.pos + i) {
+				Some(&b) => b,
+				None => return Err(WireError::UnexpectedEnd),
+			};
+
+			// The tenth byte only has room for the top bit
+			if i == 9 && byte > 1 {
+				return Err(WireError::InvalidVarint);
+			}
+
+			// Each value has exactly one encoding, so a trailing zero byte is padding
+			if i > 0 && byte == 0 {
+				return Err(WireError::InvalidVarint);
+			}
+
+			value |= ((byte & 0x7F) as u64) << (i * 7);
+
+			if byte & 0x80 == 0 {
+				self.pos += i + 1;
+				return Ok(value);
+			}
+		}
+
+		Err(WireError::InvalidVarint)
+	}
+
+	pub fn read_prefixed_bytes(&mut self) -> Result<&'a [u8], WireError> {
+		let start = self.pos;
+		let len = self.read_varint()?;
+
+		if len > self.remaining() as u64 {
+			self.pos = start;
+			return Err(WireError::UnexpectedEnd);
+		}
+
+		self.read_bytes(len as usize)
+	}
+
+	pub fn read_prefixed_str(&mut self) -> Result<&'a str, WireError> {
+		let start = self.pos;
+		let bytes = self.read_prefixed_bytes()?;
+
+		str::from_utf8(bytes).map_err(|_| {
+			self.pos = start;
+			WireError::InvalidUtf8
+		})
+	}
+}
+
+// Appends values to the end of a buffer
+pub struct Writer<'a> {
+	buf: &'a mut Vec<u8>,
+}
+
+impl<'a> Writer<'a> {
+	pub fn new(buf: &'a mut Vec<u8>) -> Self {
+		Writer { buf }
+	}
+
+	// How much has been written to the buffer, including anything already there
+	pub fn len(&self) -> usize {
+		self.buf.len()
+	}
+
+	pub fn write_bytes(&mut self, bytes: &[u8]) {
+		self.buf.extend_from_slice(bytes);
+	}
+
+	pub fn write_str(&mut self, s: &str) {
+		self.write_bytes(s.as_bytes());
+	}
+
+	fn write_le(&mut self, value: u64, len: usize) {
+		self.buf.extend((0..len).map(|i| (value >> (i * 8)) as u8));
+	}
+
+	pub fn write_u8(&mut self, value: u8) { self.write_le(value as u64, 1) }
+	pub fn write_u16(&mut self, value: u16) { self.write_le(value as u64, 2) }
+	pub fn write_u32(&mut self, value: u32) { self.write_le(value as u64, 4) }
+	pub fn write_u64(&mut self, value: u64) { self.write_le(value, 8) }
+	pub fn write_i32(&mut self, value: i32) { self.write_u32(value as u32) }
+	pub fn write_f32(&mut self, value: f32) { self.write_u32(value.to_bits()) }
+	pub fn write_f64(&mut self, value: f64) { self.write_u64(value.to_bits()) }
+
+	pub fn write_varint(&mut self, mut value: u64) {
+		while value >= 0x80 {
+			self.buf.push(value as u8 | 0x80);
+			value >>= 7;
+		}
+
+		self.buf.push(value as u8);
+	}
+
+	pub fn write_prefixed_bytes(&mut self, bytes: &[u8]) {
+		self.write_varint(bytes.len() as u64);
+		self.write_bytes(bytes);
+	}
+
+	pub fn write_prefixed_str(&mut self, s: &str) {
+		self.write_prefixed_bytes(s.as_bytes());
+	}
+}
+
+// Values that know how to put themselves on the wire
+pub trait Encode {
+	fn encode(&self, writer: &mut Writer);
+}
+
+pub trait Decode: Sized {
+	fn decode(reader: &mut Reader) -> Result<Self, WireError>;
+}
+
+macro_rules! impl_wire_primitive {
+	($($ty:ty => $write:ident, $read:ident;)*) => {$(
+		impl Encode for $ty {
+			fn encode(&self, writer: &mut Writer) { writer.$write(*self) }
+		}
+
+		impl Decode for $ty {
+			fn decode(reader: &mut Reader) -> Result<Self, WireError> { reader.$read() }
+		}
+	)*}
+}
+
+impl_wire_primitive! {
+	u8 => write_u8, read_u8;
+	u16 => write_u16, read_u16;
+	u32 => write_u32, read_u32;
+	u64 => write_u64, read_u64;
+	i32 => write_i32, read_i32;
+	f32 => write_f32, read_f32;
+	f64 => write_f64, read_f64;
+}
+
+impl Encode for String {
+	fn encode(&self, writer: &mut Writer) { writer.write_prefixed_str(self) }
+}
+
+impl Decode for String {
+	fn decode(reader: &mut Reader) -> Result<Self, WireError> {
+		reader.read_prefixed_str().map(String::from)
+	}
+}
+
+// A varint count, then each element
+impl<T: Encode> Encode for Vec<T> {
+	fn encode(&self, writer: &mut Writer) {
+		writer.write_varint(self.len() as u64);
+		for item in self {
+			item.encode(writer);
+		}
+	}
+}
+
+impl<T: Decode> Decode for Vec<T> {
+	fn decode(reader: &mut Reader) -> Result<Self, WireError> {
+		let count = reader.read_varint()?;
+
+		// The count can't be trusted, so let the reads run out rather than reserving for it
+		let mut items = Vec::new();
+		for _ in 0..count {
+			items.push(T::decode(reader)?);
+		}
+
+		Ok(items)
+	}
+}
+
+impl Encode for Vec2 {
+	fn encode(&self, writer: &mut Writer) {
+		self.x.encode(writer);
+		self.y.encode(writer);
+	}
+}
+
+impl Decode for Vec2 {
+	fn decode(reader: &mut Reader) -> Result<Self, WireError> {
+		Ok(Vec2::new(f32::decode(reader)?, f32::decode(reader)?))
+	}
+}
+
+impl Encode for Vec3 {
+	fn encode(&self, writer: &mut Writer) {
+		self.x.encode(writer);
+		self.y.encode(writer);
+		self.z.encode(writer);
+	}
+}
+
+impl Decode for Vec3 {
+	fn decode(reader: &mut Reader) -> Result<Self, WireError> {
+		Ok(Vec3::new(f32::decode(reader)?, f32::decode(reader)?, f32::decode(reader)?))
+	}
+}
+
+impl Encode for Vec2i {
+	fn encode(&self, writer: &mut Writer) {
+		self.x.encode(writer);
+		self.y.encode(writer);
+	}
+}
+
+impl Decode for Vec2i {
+	fn decode(reader: &mut Reader) -> Result<Self, WireError> {
+		Ok(Vec2i::new(i32::decode(reader)?, i32::decode(reader)?))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn integers_are_little_endian() {
+		let mut buf = Vec::new();
+		{
+			let mut w = Writer::new(&mut buf);
+			w.write_u16(0x0102);
+			w.write_u32(0x03040506);
+			w.write_i32(-2);
+		}
+
+		assert_eq!(buf, [0x02, 0x01, 0x06, 0x05, 0x04, 0x03, 0xFE, 0xFF, 0xFF, 0xFF]);
+
+		let mut r = Reader::new(&buf);
+		assert_eq!(r.read_u16(), Ok(0x0102));
+		assert_eq!(r.read_u32(), Ok(0x03040506));
+		assert_eq!(r.read_i32(), Ok(-2));
+		assert_eq!(r.finish(), Ok(()));
+	}
+
+	#[test]
+	fn values_round_trip() {
+		let mut buf = Vec::new();
+		{
+			let mut w = Writer::new(&mut buf);
+			w.write_u8(0xAB);
+			w.write_u64(::std::u64::MAX - 1);
+			w.write_f32(-1.5);
+			w.write_f64(::std::f64::consts::PI);
+			w.write_prefixed_str("héllo");
+			w.write_prefixed_bytes(&[1, 2, 3]);
+			w.write_str("rest");
+		}
+
+		let mut r = Reader::new(&buf);
+		assert_eq!(r.read_u8(), Ok(0xAB));
+		assert_eq!(r.read_u64(), Ok(::std::u64::MAX - 1));
+		assert_eq!(r.read_f32(), Ok(-1.5));
+		assert_eq!(r.read_f64(), Ok(::std::f64::consts::PI));
+		assert_eq!(r.read_prefixed_str(), Ok("héllo"));
+		assert_eq!(r.read_prefixed_bytes(), Ok(&[1u8, 2, 3][..]));
+
+		let len = r.remaining();
+		assert_eq!(r.read_str(len), Ok("rest"));
+		assert_eq!(r.finish(), Ok(()));
+	}
+
+	#[test]
+	fn varints_round_trip() {
+		for &value in &[0, 1, 127, 128, 300, 1<<32, ::std::u64::MAX] {
+			let mut buf = Vec::new();
+			Writer::new(&mut buf).write_varint(value);
+
+			let mut r = Reader::new(&buf);
+			assert_eq!(r.read_varint(), Ok(value));
+			assert_eq!(r.finish(), Ok(()));
+		}
+
+		let mut buf = Vec::new();
+		Writer::new(&mut buf).write_varint(300);
+		assert_eq!(buf, [0xAC, 0x02]);
+	}
+
+	#[test]
+	fn failed_reads_consume_nothing() {
+		let mut r = Reader::new(&[1, 2, 3]);
+		assert_eq!(r.read_u32(), Err(WireError::UnexpectedEnd));
+		assert_eq!(r.remaining(), 3);
+
+		// Claims 5 bytes, has 2
+		let mut r = Reader::new(&[5, b'a', b'b']);
+		assert_eq!(r.read_prefixed_str(), Err(WireError::UnexpectedEnd));
+		assert_eq!(r.remaining(), 3);
+
+		let mut r = Reader::new(&[2, 0xC3, 0x28]);
+		assert_eq!(r.read_prefixed_str(), Err(WireError::InvalidUtf8));
+		assert_eq!(r.remaining(), 3);
+
+		assert_eq!(Reader::new(&[1, 2]).finish(), Err(WireError::TrailingBytes(2)));
+	}
+
+	#[test]
+	fn bad_varints_are_rejected() {
+		assert_eq!(Reader::new(&[0x80, 0x80]).read_varint(), Err(WireError::UnexpectedEnd));
+		assert_eq!(Reader::new(&[0xFF; 10]).read_varint(), Err(WireError::InvalidVarint));
+		assert_eq!(Reader::new(&[0x80, 0x00]).read_varint(), Err(WireError::InvalidVarint));
+		assert_eq!(Reader::new(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02]).read_varint(), Err(WireError::InvalidVarint));
+		assert_eq!(Reader::new(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]).read_varint(), Ok(::std::u64::MAX));
+
+		// A huge length shouldn't be trusted
+		let mut buf = Vec::new();
+		Writer::new(&mut buf).write_varint(::std::u64::MAX);
+		assert_eq!(Reader::new(&buf).read_prefixed_bytes(), Err(WireError::UnexpectedEnd));
+	}
+
+	#[test]
+	fn encodables_round_trip() {
+		let mut buf = Vec::new();
+		{
+			let mut w = Writer::new(&mut buf);
+			Vec2::new(1.0, -2.0).encode(&mut w);
+			Vec3::new(0.5, 0.25, 8.0).encode(&mut w);
+			Vec2i::new(-7, 1<<20).encode(&mut w);
+			vec![String::from("a"), String::from("bc")].encode(&mut w);
+		}
+
+		let mut r = Reader::new(&buf);
+		let v = Vec2::decode(&mut r).unwrap();
+		assert!(v.x == 1.0 && v.y == -2.0);
+
+		let v = Vec3::decode(&mut r).unwrap();
+		assert!(v.x == 0.5 && v.y == 0.25 && v.z == 8.0);
+
+		let v = Vec2i::decode(&mut r).unwrap();
+		assert!(v.x == -7 && v.y == 1<<20);
+
+		assert_eq!(Vec::<String>::decode(&mut r), Ok(vec![String::from("a"), String::from("bc")]));
+		assert_eq!(r.finish(), Ok(()));
+
+		// A count far bigger than the data just runs out
+		let mut buf = Vec::new();
+		Writer::new(&mut buf).write_varint(1<<40);
+		assert_eq!(Vec::<u32>::decode(&mut Reader::new(&buf)), Err(WireError::UnexpectedEnd));
+	}
+}
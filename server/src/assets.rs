@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, Duration, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::write::{GzEncoder, DeflateEncoder};
+use http;
+use sha1;
+
+// Smaller files are always sent as they are, since compressing them saves next to nothing
+pub const MIN_COMPRESS_LEN: u64 = 1<<10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+	Identity,
+	Gzip,
+	Deflate,
+
+	// Only ever sent from a precompressed sidecar file, since there's nothing here to compress it with
+	Brotli,
+}
+
+// A file the way it's sent, already compressed
+#[derive(Debug)]
+pub struct Asset {
+	pub body: Vec<u8>,
+	pub encoding: Encoding,
+	pub etag: String,
+
+	// The file the body came from, which is a sidecar if it was precompressed.
+	// Its size and modification time say whether it's changed since it was loaded
+	source: PathBuf,
+	modified: SystemTime,
+	file_len: u64,
+}
+
+// Files are read and compressed once, and then again only after they change on disk.
+// Each encoding of a file is cached separately, and whatever was used longest ago makes way
+// once the bodies add up to more than max_bytes
+pub struct AssetCache {
+	entries: Mutex<CacheEntries>,
+	max_bytes: usize,
+}
+
+struct CacheEntries {
+	assets: HashMap<(PathBuf, Encoding), (Arc<Asset>, u64)>,
+	bytes: usize,
+	uses: u64,
+}
+
+impl Encoding {
+	// As it's named in Accept-Encoding and Content-Encoding
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Encoding::Identity => "identity",
+			Encoding::Gzip => "gzip",
+			Encoding::Deflate => "deflate",
+			Encoding::Brotli => "br",
+		}
+	}
+
+	// The extension of a precompressed copy kept alongside a file, as in wsclient.js.gz
+	fn sidecar_extension(&self) -> Option<&'static str> {
+		match *self {
+			Encoding::Gzip => Some("gz"),
+			Encoding::Brotli => Some("br"),
+			_ => None
+		}
+	}
+}
+
+// The encodings a file can be sent in, in the order the server prefers them
+pub fn available_encodings(path: &Path) -> io::Result<Vec<Encoding>> {
+	if fs::metadata(path)?.len() < MIN_COMPRESS_LEN {
+		return Ok(vec![Encoding::Identity]);
+	}
+
+	let mut encodings = Vec::new();
+	if sidecar(path, Encoding::Brotli).is_some() {
+		encodings.push(Encoding::Brotli);
+	}
+
+	encodings.extend_from_slice(&[Encoding::Gzip, Encoding::Deflate, Encoding::Identity]);
+	Ok(encodings)
+}
+
+// Picks whichever available encoding the client weights highest, going by the server's preference
+// where they tie. Without an Accept-Encoding the file is sent as it is. Identity is acceptable unless
+// it's refused, either by name or by `*`. None means the client refused everything available
+pub fn negotiate(accept_encoding: &[&str], available: &[Encoding]) -> Option<Encoding> {
+	if accept_encoding.is_empty() {
+		return available.iter().cloned().find(|&e| e == Encoding::Identity);
+	}
+
+	let weights = http::weighted_list(accept_encoding);
+
+	let weight_of = |encoding: Encoding| {
+		let weight_named = |name: &str| weights.iter()
+			.find(|&&(coding, _)| coding.eq_ignore_ascii_case(name))
+			.map(|&(_, weight)| weight);
+
+		let named = match encoding {
+			Encoding::Gzip => weight_named("gzip").or(weight_named("x-gzip")),
+			_ => weight_named(encoding.name()),
+		};
+
+		named.or(weight_named("*"))
+			.unwrap_or(if encoding == Encoding::Identity { 1 } else { 0 })
+	};
+
+	let mut best = None;
+	for &encoding in available {
+		let weight = weight_of(encoding);
+		if weight > 0 && best.map_or(true, |(_, best_weight)| weight > best_weight) {
+			best = Some((encoding, weight));
+		}
+	}
+
+	best.map(|(encoding, _)| encoding)
+}
+
+// A sidecar is only used if it's a regular file, since a symlink could lead outside the static root,
+// and if it's no older than the file it's a copy of
+fn sidecar(path: &Path, encoding: Encoding) -> Option<PathBuf> {
+	let mut name = path.file_name()?.to_os_string();
+	name.push(".");
+	name.push(encoding.sidecar_extension()?);
+
+	let sidecar = path.with_file_name(name);
+	let metadata = fs::symlink_metadata(&sidecar).ok()?;
+
+	if metadata.file_type().is_file() && metadata.modified().ok()? >= fs::metadata(path).ok()?.modified().ok()? {
+		Some(sidecar)
+	} else {
+		None
+	}
+}
+
+impl Asset {
+	// A source other than path is a sidecar, which is already encoded
+	fn load(path: &Path, source: &Path, encoding: Encoding) -> io::Result<Asset> {
+		let mut f = File::open(source)?;
+		let metadata = f.metadata()?;
+
+		let mut data = Vec::new();
+		f.read_to_end(&mut data)?;
+
+		let mut m = sha1::Sha1::new();
+		m.update(&data);
+		let hash = m.digest().bytes()[..8].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+		// Each encoding is a different representation, so it needs a different tag
+		let etag = match encoding {
+			Encoding::Identity => format!("\"{}\"", hash),
+			_ => format!("\"{}-{}\"", hash, encoding.name()),
+		};
+
+		let body = match encoding {
+			_ if source != path => data,
+			Encoding::Identity => data,
+
+			Encoding::Gzip => {
+				let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+				encoder.write_all(&data)?;
+				encoder.finish()?
+			}
+
+			Encoding::Deflate => {
+				let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+				encoder.write_all(&data)?;
+				encoder.finish()?
+			}
+
+			Encoding::Brotli =>
+				return Err(io::Error::new(io::ErrorKind::NotFound, "No precompressed brotli file")),
+		};
+
+		Ok(Asset {
+			body: body,
+			encoding: encoding,
+			etag: etag,
+			source: source.to_path_buf(),
+			modified: metadata.modified()?,
+			file_len: metadata.len(),
+		})
+	}
+
+	// Truncated to whole seconds, which is all an http date can carry
+	pub fn last_modified(&self) -> SystemTime {
+		let secs = self.modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+		UNIX_EPOCH + Duration::from_secs(secs)
+	}
+}
+
+impl AssetCache {
+	pub fn new(max_bytes: usize) -> AssetCache {
+		AssetCache {
+			entries: Mutex::new(CacheEntries {
+				assets: HashMap::new(),
+				bytes: 0,
+				uses: 0,
+			}),
+			max_bytes: max_bytes,
+		}
+	}
+
+	// Prefers a precompressed sidecar if there is one for the encoding.
+	// A file that can't be read any more is forgotten
+	pub fn get(&self, path: &Path, encoding: Encoding) -> io::Result<Arc<Asset>> {
+		let key = (path.to_path_buf(), encoding);
+
+		let result = self.get_fresh(&key);
+		if result.is_err() {
+			self.entries.lock().unwrap().remove(&key);
+		}
+
+		result
+	}
+
+	fn get_fresh(&self, key: &(PathBuf, Encoding)) -> io::Result<Arc<Asset>> {
+		let (ref path, encoding) = *key;
+		let source = sidecar(path, encoding).unwrap_or(path.to_path_buf());
+		let metadata = fs::metadata(&source)?;
+
+		if let Some(asset) = self.entries.lock().unwrap().get(key) {
+			if asset.source == source && asset.modified == metadata.modified()? && asset.file_len == metadata.len() {
+				return Ok(asset);
+			}
+		}
+
+		// Loaded without holding the lock, so that other requests aren't held up by compression
+		let asset = Arc::new(Asset::load(path, &source, encoding)?);
+		self.entries.lock().unwrap().insert(key.clone(), asset.clone(), self.max_bytes);
+		Ok(asset)
+	}
+}
+
+impl CacheEntries {
+	fn get(&mut self, key: &(PathBuf, Encoding)) -> Option<Arc<Asset>> {
+		self.uses += 1;
+		let uses = self.uses;
+
+		self.assets.get_mut(key).map(|entry| {
+			entry.1 = uses;
+			entry.0.clone()
+		})
+	}
+
+	fn remove(&mut self, key: &(PathBuf, Encoding)) {
+		if let Some((asset, _)) = self.assets.remove(key) {
+			self.bytes -= asset.body.len();
+		}
+	}
+
+	// Anything bigger than the whole cache is still served, just not kept
+	fn insert(&mut self, key: (PathBuf, Encoding), asset: Arc<Asset>, max_bytes: usize) {
+		self.remove(&key);
+		if asset.body.len() > max_bytes { return }
+
+		while self.bytes + asset.body.len() > max_bytes {
+			let oldest = self.assets.iter()
+				.min_by_key(|&(_, &(_, last_used))| last_used)
+				.map(|(key, _)| key.clone())
+				.unwrap();
+
+			self.remove(&oldest);
+		}
+
+		self.uses += 1;
+		self.bytes += asset.body.len();
+		self.assets.insert(key, (asset, self.uses));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use flate2::read::GzDecoder;
+
+	#[test]
+	fn assets_are_reloaded_when_they_change() {
+		let path = env::temp_dir().join(format!("wsserver-asset-{}.txt", ::std::process::id()));
+		fs::write(&path, "first version").unwrap();
+
+		let cache = AssetCache::new(1<<20);
+		let first = cache.get(&path, Encoding::Identity).unwrap();
+		assert!(first.body == b"first version");
+		assert!(Arc::ptr_eq(&first, &cache.get(&path, Encoding::Identity).unwrap()));
+
+		// Each encoding is its own entry, with its own tag
+		let gzipped = cache.get(&path, Encoding::Gzip).unwrap();
+		assert!(gzipped.etag != first.etag);
+
+		let mut decoded = String::new();
+		GzDecoder::new(&gzipped.body[..]).unwrap().read_to_string(&mut decoded).unwrap();
+		assert!(decoded == "first version");
+
+		// The same size, so only the timestamp gives it away
+		fs::write(&path, "later version").unwrap();
+		File::options().write(true).open(&path).unwrap()
+			.set_modified(first.modified + Duration::from_secs(2)).unwrap();
+
+		let second = cache.get(&path, Encoding::Identity).unwrap();
+		assert!(second.body == b"later version");
+		assert!(second.etag != first.etag);
+		assert!(second.last_modified() > first.last_modified());
+
+		fs::remove_file(&path).unwrap();
+		assert!(cache.get(&path, Encoding::Identity).is_err());
+		assert!(cache.entries.lock().unwrap().assets.get(&(path.clone(), Encoding::Identity)).is_none());
+	}
+
+	#[test]
+	fn the_least_recently_used_assets_make_way() {
+		let dir = env::temp_dir().join(format!("wsserver-asset-lru-{}", ::std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+
+		let paths = (0..4).map(|i| dir.join(format!("{}.txt", i))).collect::<Vec<_>>();
+		for path in &paths {
+			fs::write(path, vec![b'a'; 100]).unwrap();
+		}
+
+		// Room for three
+		let cache = AssetCache::new(350);
+		let cached = |path: &PathBuf| cache.entries.lock().unwrap().assets.contains_key(&(path.clone(), Encoding::Identity));
+
+		for path in &paths[..3] {
+			cache.get(path, Encoding::Identity).unwrap();
+		}
+
+		cache.get(&paths[0], Encoding::Identity).unwrap();
+		cache.get(&paths[3], Encoding::Identity).unwrap();
+		assert!(cached(&paths[0]) && !cached(&paths[1]) && cached(&paths[2]) && cached(&paths[3]));
+		assert_eq!(cache.entries.lock().unwrap().bytes, 300);
+
+		// Too big to keep at all, but still served
+		fs::write(&paths[1], vec![b'a'; 400]).unwrap();
+		assert_eq!(cache.get(&paths[1], Encoding::Identity).unwrap().body.len(), 400);
+		assert!(!cached(&paths[1]));
+		assert_eq!(cache.entries.lock().unwrap().bytes, 300);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn negotiation_follows_weights_then_preference() {
+		use self::Encoding::*;
+		let all = &[Brotli, Gzip, Deflate, Identity];
+
+		assert!(negotiate(&[], all) == Some(Identity));
+		assert!(negotiate(&[""], all) == Some(Identity));
+		assert!(negotiate(&["gzip, br"], all) == Some(Brotli));
+		assert!(negotiate(&["gzip, br;q=0.9"], all) == Some(Gzip));
+		assert!(negotiate(&["X-GZIP"], all) == Some(Gzip));
+		assert!(negotiate(&["deflate;q=0.5", "*"], all) == Some(Brotli));
+		assert!(negotiate(&["*;q=0.5, identity"], all) == Some(Identity));
+		assert!(negotiate(&["*;q=0"], all) == None);
+		assert!(negotiate(&["identity;q=0"], &[Identity]) == None);
+		assert!(negotiate(&["gzip"], &[Identity]) == Some(Identity));
+
+		// A weight that can't be read leaves the coding unmentioned, rather than refused or preferred
+		assert!(negotiate(&["gzip;q=2, deflate;q=0.5"], all) == Some(Deflate));
+	}
+}
@@ -0,0 +1,158 @@
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::fmt::Display;
+use std::time::Duration;
+
+// Server settings, loaded from a file of `key = value` lines.
+// Anything not mentioned in the file keeps its default
+#[derive(Clone, Debug)]
+pub struct Config {
+	// Largest message a client can send, after reassembling fragments
+	pub max_message_size: usize,
+
+	// Limits on the upgrade request a client sends before becoming a websocket
+	pub max_handshake_size: usize,
+	pub handshake_timeout: Duration,
+
+	// How long the file server keeps a connection open waiting for another request,
+	// and how long a client has to send the whole of each request once it's started
+	pub http_idle_timeout: Duration,
+	pub http_request_timeout: Duration,
+
+	// Connections the file server handles at once, each on a thread of its own.
+	// Any more are turned away with a 503
+	pub max_http_connections: usize,
+
+	// How long a connection can go without being pinged,
+	// and how long it then has to respond before being dropped
+	pub ping_interval: Duration,
+	pub pong_timeout: Duration,
+
+	// Once this many bytes are waiting to be sent to a client, droppable packets are discarded.
+	// Past send_queue_limit the client is disconnected
+	pub send_queue_high_water: usize,
+	pub send_queue_limit: usize,
+
+	// Origins websockets can be opened from, e.g. `https://example.com`.
+	// Comma separated in the file. If empty, any origin is allowed
+	pub allowed_origins: Vec<String>,
+
+	// Directories the file server serves files from, searched in order. Everything in them is public,
+	// so they should never be a crate root with source and build files in it. Comma separated in the file
+	pub static_roots: Vec<String>,
+
+	// How many bytes of files, compressed or not, the file server keeps in memory
+	pub asset_cache_size: usize,
+
+	// PEM files for serving https and wss. Both must be set to enable TLS
+	pub tls_cert_path: Option<String>,
+	pub tls_key_path: Option<String>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			max_message_size: 1<<20,
+
+			max_handshake_size: 8<<10,
+			handshake_timeout: Duration::from_secs(5),
+
+			http_idle_timeout: Duration::from_secs(5),
+			http_request_timeout: Duration::from_secs(10),
+			max_http_connections: 64,
+
+			ping_interval: Duration::from_secs(15),
+			pong_timeout: Duration::from_secs(10),
+
+			send_queue_high_water: 256<<10,
+			send_queue_limit: 4<<20,
+
+			allowed_origins: Vec::new(),
+
+			static_roots: vec![
+				"../client/static".to_string(),
+				if cfg!(hosted) {
+					"../client/target/asmjs-unknown-emscripten/release".to_string()
+				} else {
+					"../client/target/asmjs-unknown-emscripten/debug".to_string()
+				},
+			],
+
+			asset_cache_size: 64<<20,
+
+			tls_cert_path: None,
+			tls_key_path: None,
+		}
+	}
+}
+
+impl Config {
+	pub fn load(path: &str) -> Config {
+		let mut config = Config::default();
+		let mut data = String::new();
+
+		if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut data)) {
+			println!("Couldn't read config '{}', using defaults: {}", path, e);
+			return config;
+		}
+
+		for (line_no, line) in data.lines().enumerate() {
+			let line = line.split('#').next().unwrap().trim();
+			if line.is_empty() { continue }
+
+			let mut kv = line.splitn(2, '=').map(|s| s.trim());
+			let key = kv.next().unwrap();
+			let value = match kv.next() {
+				Some(v) => v,
+				None => {
+					println!("Config '{}' line {}: expected 'key = value'", path, line_no+1);
+					continue
+				}
+			};
+
+			if let Err(e) = config.set(key, value) {
+				println!("Config '{}' line {}: {}", path, line_no+1, e);
+			}
+		}
+
+		config
+	}
+
+	fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+		match key {
+			"max_message_size" => self.max_message_size = parse_value(value)?,
+			"max_handshake_size" => self.max_handshake_size = parse_value(value)?,
+			"handshake_timeout_ms" => self.handshake_timeout = Duration::from_millis(parse_value(value)?),
+			"http_idle_timeout_ms" => self.http_idle_timeout = Duration::from_millis(parse_value(value)?),
+			"http_request_timeout_ms" => self.http_request_timeout = Duration::from_millis(parse_value(value)?),
+			"max_http_connections" => self.max_http_connections = parse_value(value)?,
+			"ping_interval_ms" => self.ping_interval = Duration::from_millis(parse_value(value)?),
+			"pong_timeout_ms" => self.pong_timeout = Duration::from_millis(parse_value(value)?),
+			"send_queue_high_water" => self.send_queue_high_water = parse_value(value)?,
+			"send_queue_limit" => self.send_queue_limit = parse_value(value)?,
+			"allowed_origins" => self.allowed_origins = value.split(',')
+				.map(str::trim)
+				.filter(|s| !s.is_empty())
+				.map(String::from)
+				.collect(),
+
+			"static_roots" => self.static_roots = value.split(',')
+				.map(str::trim)
+				.filter(|s| !s.is_empty())
+				.map(String::from)
+				.collect(),
+
+			"asset_cache_size" => self.asset_cache_size = parse_value(value)?,
+			"tls_cert_path" => self.tls_cert_path = Some(value.to_string()),
+			"tls_key_path" => self.tls_key_path = Some(value.to_string()),
+			_ => return Err(format!("Unknown key '{}'", key)),
+		}
+
+		Ok(())
+	}
+}
+
+fn parse_value<T>(value: &str) -> Result<T, String> where T: FromStr, T::Err: Display {
+	value.parse().map_err(|e| format!("Invalid value '{}': {}", value, e))
+}
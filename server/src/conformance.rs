@@ -0,0 +1,1219 @@
+// RFC 6455 conformance tests, in the spirit of the Autobahn testsuite.
+// Each test runs a ConnectionManager on a loopback port and talks to it through a plain socket,
+// checking that bad input gets the right close status rather than taking the server down
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Poll, Events, Token, Ready, PollOpt};
+use common::{Packet, PacketError, Batch, BATCH_TYPE, PROTOCOL_VERSION};
+use connections::ConnectionManager;
+use config::Config;
+use deflate::{Deflater, Inflater};
+use fileserver;
+use ws;
+
+const LISTENER_TOKEN: Token = Token(::std::usize::MAX - 1);
+
+// A cut down network_loop. Everyone who tries to auth is let in,
+// and debug packets are echoed back so messages can be checked in both directions.
+// Anything that isn't an upgrade is handed to a file server, as it is in main
+struct TestServer {
+	addr: SocketAddr,
+	packets: mpsc::Receiver<Packet>,
+	running: Arc<AtomicBool>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestServer {
+	fn start() -> TestServer {
+		TestServer::with_config(Config::default())
+	}
+
+	fn with_config(config: Config) -> TestServer {
+		let listener = mio::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let (tx, packets) = mpsc::channel();
+		let (fs_tx, fs_rx) = mpsc::channel();
+		let fs_config = config.clone();
+		thread::spawn(move || fileserver::start(fs_rx, fs_config));
+
+		let running = Arc::new(AtomicBool::new(true));
+		let still_running = running.clone();
+
+		let thread = thread::spawn(move || {
+			let poll = Poll::new().unwrap();
+			poll.register(&listener, LISTENER_TOKEN, Ready::readable(), PollOpt::edge()).unwrap();
+
+			let mut events = Events::with_capacity(64);
+			let mut connections = ConnectionManager::new(config, None);
+			let mut read_buffer = [0u8; 8<<10];
+
+			while still_running.load(Ordering::SeqCst) {
+				poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+
+				for event in events.iter() {
+					match event.token() {
+						LISTENER_TOKEN => while let Ok((stream, _)) = listener.accept_std() {
+							connections.register_connection(&poll, stream);
+						},
+
+						Token(id) => {
+							connections.handle_readable(id as u32, &mut read_buffer);
+							connections.handle_writable(id as u32);
+						}
+					}
+				}
+
+				while let Some((id, packet)) = connections.try_read() {
+					if let Packet::Debug(_) = packet {
+						if let Some(con) = connections.connections.iter_mut().find(|c| c.id == id) {
+							con.send_packet(&packet);
+						}
+					}
+
+					let _ = tx.send(packet);
+				}
+
+				while let Some(request) = connections.poll_http_requests(&poll) {
+					fs_tx.send(request).unwrap();
+				}
+
+				while let Some((id, token)) = connections.poll_auth_attempts() {
+					if connections.imbue_session(id, token) {
+						connections.send_to(id, &Packet::AuthSuccessful(token));
+					}
+				}
+
+				connections.send_heartbeats();
+				connections.flush();
+			}
+		});
+
+		TestServer {
+			addr,
+			packets,
+			running,
+			thread: Some(thread),
+		}
+	}
+
+	fn next_packet(&self) -> Packet {
+		self.packets.recv_timeout(Duration::from_secs(5)).expect("No packet received")
+	}
+}
+
+impl Drop for TestServer {
+	fn drop(&mut self) {
+		self.running.store(false, Ordering::SeqCst);
+
+		// Don't hide the original failure behind a second panic
+		if let Some(thread) = self.thread.take() {
+			if thread.join().is_err() && !thread::panicking() {
+				panic!("Server thread panicked");
+			}
+		}
+	}
+}
+
+struct TestClient {
+	stream: net::TcpStream,
+	buffer: Vec<u8>,
+
+	// The rest of a batch that's been partly looked at
+	packets: VecDeque<Packet>,
+}
+
+impl TestClient {
+	fn connect(server: &TestServer) -> TestClient {
+		let stream = net::TcpStream::connect(server.addr).unwrap();
+		stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		stream.set_nodelay(true).unwrap();
+
+		TestClient {
+			stream,
+			buffer: Vec::new(),
+			packets: VecDeque::new(),
+		}
+	}
+
+	// Connects and completes the opening handshake
+	fn upgrade(server: &TestServer) -> TestClient {
+		let mut client = TestClient::connect(server);
+		let response = client.request(&upgrade_request(&[]));
+		assert!(response.starts_with("HTTP/1.1 101 "), "Upgrade refused: {}", response);
+		client
+	}
+
+	// Upgrades and agrees on a protocol version
+	fn greeted(server: &TestServer) -> TestClient {
+		let mut client = TestClient::upgrade(server);
+		client.send_packet(&Packet::Hello(PROTOCOL_VERSION, String::from("test")));
+
+		let frame = client.read_frame().expect("Connection closed during hello");
+		assert_eq!(frame.opcode, 0x2);
+		assert!(match_enum!(Packet::parse(&frame.payload), Ok(Packet::ServerHello(PROTOCOL_VERSION))));
+
+		client
+	}
+
+	// Greets and waits to be let in, so packets other than auth attempts are accepted
+	fn authenticated(server: &TestServer) -> TestClient {
+		let mut client = TestClient::greeted(server);
+		client.send_packet(&Packet::AttemptAuthSession(123));
+
+		let frame = client.read_frame().expect("Connection closed during auth");
+		assert_eq!(frame.opcode, 0x2);
+		assert_eq!(frame.payload[0], Packet::AuthSuccessful(0).get_type());
+
+		client
+	}
+
+	// Sends an http request, and returns the response header. Any body is skipped
+	fn request(&mut self, request: &str) -> String {
+		self.send_raw(request.as_bytes());
+
+		while !self.buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+			if !self.fill_buffer() { break }
+		}
+
+		let end = self.buffer.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4)
+			.unwrap_or(self.buffer.len());
+
+		let response = self.buffer.drain(..end).collect::<Vec<_>>();
+		let response = String::from_utf8(response).unwrap();
+
+		let body_len = response.lines()
+			.filter(|l| l.to_lowercase().starts_with("content-length:"))
+			.filter_map(|l| l["content-length:".len()..].trim().parse::<usize>().ok())
+			.next().unwrap_or(0);
+
+		while self.buffer.len() < body_len {
+			if !self.fill_buffer() { break }
+		}
+
+		let body_len = body_len.min(self.buffer.len());
+		self.buffer.drain(..body_len);
+		response
+	}
+
+	fn send_raw(&mut self, data: &[u8]) {
+		self.stream.write_all(data).unwrap();
+	}
+
+	fn send_frame(&mut self, fin: bool, rsv: u8, opcode: u8, payload: &[u8]) {
+		let frame = client_frame(fin, rsv, opcode, payload);
+		self.send_raw(&frame);
+	}
+
+	fn send_packet(&mut self, packet: &Packet) {
+		let mut payload = Vec::new();
+		packet.write(&mut payload);
+		self.send_frame(true, 0, 0x2, &payload);
+	}
+
+	// Returns false once the server has closed the connection
+	fn fill_buffer(&mut self) -> bool {
+		let mut data = [0u8; 8<<10];
+
+		match self.stream.read(&mut data) {
+			Ok(0) => false,
+			Ok(len) => {
+				self.buffer.extend_from_slice(&data[..len]);
+				true
+			}
+
+			Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => false,
+			Err(e) => panic!("Read failed: {}", e),
+		}
+	}
+
+	// The next frame from the server, or None if the connection was closed
+	fn read_frame(&mut self) -> Option<ws::Frame> {
+		loop {
+			if let Some((frame, len)) = ws::decode_ws_packet(&self.buffer).expect("Server sent an invalid frame") {
+				self.buffer.drain(..len);
+				return Some(frame);
+			}
+
+			if !self.fill_buffer() {
+				return None;
+			}
+		}
+	}
+
+	fn expect_close(&mut self, status: u16) {
+		let frame = self.read_frame().expect("Connection dropped without a close frame");
+		assert_eq!(frame.opcode, 0x8, "Expected a close frame");
+		assert!(!frame.masked, "Server frames must not be masked");
+		assert!(frame.payload.len() >= 2, "Close frame has no status");
+
+		let received = (frame.payload[0] as u16) << 8 | frame.payload[1] as u16;
+		assert_eq!(received, status);
+	}
+
+	fn expect_disconnect(&mut self) {
+		assert!(self.read_frame().is_none(), "Expected the connection to be dropped");
+	}
+
+	// The next packet from the server, whether or not it came in a batch
+	fn read_packet(&mut self) -> Option<Packet> {
+		if self.packets.is_empty() {
+			let frame = self.read_frame()?;
+			assert_eq!(frame.opcode, 0x2);
+			assert!(frame.final_frame);
+
+			let packets = Packet::parse_message(&frame.payload).expect("Server sent an invalid packet");
+			self.packets.extend(packets);
+		}
+
+		self.packets.pop_front()
+	}
+
+	fn expect_echo(&mut self, message: &str) {
+		match self.read_packet() {
+			Some(Packet::Debug(ref echo)) => assert!(echo == message, "Echo doesn't match"),
+			Some(_) => panic!("Expected a debug packet"),
+			None => panic!("Connection closed instead of echoing"),
+		}
+	}
+}
+
+fn upgrade_request(extra_headers: &[&str]) -> String {
+	let mut request = String::from("GET / HTTP/1.1\r\n\
+		Host: localhost\r\n\
+		Upgrade: websocket\r\n\
+		Connection: Upgrade\r\n\
+		Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+		Sec-WebSocket-Version: 13\r\n\
+		Sec-WebSocket-Protocol: wire.v1\r\n");
+
+	for header in extra_headers {
+		request.push_str(header);
+		request.push_str("\r\n");
+	}
+
+	request.push_str("\r\n");
+	request
+}
+
+// Frames as a client would send them, masked and with whatever bits the test wants
+fn client_frame(fin: bool, rsv: u8, opcode: u8, payload: &[u8]) -> Vec<u8> {
+	let mask = [0x37, 0xfa, 0x21, 0x3d];
+	let mut frame = vec![(fin as u8) << 7 | (rsv & 0b111) << 4 | opcode & 0xF];
+
+	match payload.len() {
+		len @ 0...125 => frame.push(0x80 | len as u8),
+		len @ 126...65535 => {
+			frame.push(0x80 | 126);
+			frame.push((len >> 8) as u8);
+			frame.push(len as u8);
+		}
+
+		len => {
+			frame.push(0x80 | 127);
+			for i in (0..8).rev() {
+				frame.push((len as u64 >> (i*8)) as u8);
+			}
+		}
+	}
+
+	frame.extend_from_slice(&mask);
+	frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+	frame
+}
+
+fn debug_payload(message: &str) -> Vec<u8> {
+	let mut payload = Vec::new();
+	Packet::Debug(message.to_string()).write(&mut payload);
+	payload
+}
+
+// Sharing a port with the file server
+
+#[test]
+fn plain_requests_go_to_the_file_server() {
+	let root = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/static");
+	let config = Config { static_roots: vec![root.to_string()], .. Config::default() };
+	let server = TestServer::with_config(config);
+	let index_len = fs::metadata(format!("{}/index.html", root)).unwrap().len();
+
+	let mut http = TestClient::connect(&server);
+	let response = http.request("GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n");
+	assert!(response.starts_with("HTTP/1.1 200 "), "Expected the file: {}", response);
+	assert!(response.contains(&format!("Content-Length: {}\r\n", index_len)));
+
+	// Upgrades on the same port still become websockets, while the other connection stays open
+	let mut ws = TestClient::greeted(&server);
+	ws.send_frame(true, 0, 0x1, b"still a websocket");
+	ws.expect_echo("still a websocket");
+
+	// From now on the file server reads the http connection itself
+	let response = http.request("GET /missing.js HTTP/1.1\r\nHost: localhost\r\n\r\n");
+	assert!(response.starts_with("HTTP/1.1 404 "));
+
+	let response = http.request("GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+	assert!(response.starts_with("HTTP/1.1 200 "));
+	assert!(response.contains(&format!("Content-Length: {}\r\n", index_len)));
+	assert!(!http.fill_buffer(), "Expected the connection to be closed");
+}
+
+#[test]
+fn upgrade_requests_arent_served_files() {
+	let root = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/static");
+	let config = Config { static_roots: vec![root.to_string()], .. Config::default() };
+	let server = TestServer::with_config(config);
+
+	// Even for a path the file server has
+	let mut client = TestClient::connect(&server);
+	let response = client.request(&upgrade_request(&[]).replace("GET / ", "GET /index.html "));
+	assert!(response.starts_with("HTTP/1.1 101 "), "Upgrade refused: {}", response);
+
+	client.send_frame(true, 0, 0x1, b"not a file");
+	client.expect_echo("not a file");
+}
+
+// Framing, without a socket
+
+#[test]
+fn encoded_lengths_round_trip() {
+	for &len in &[0usize, 1, 125, 126, 127, 65535, 65536, 100000] {
+		let payload = vec![0xAB; len];
+		let mut buf = Vec::new();
+		ws::encode_ws_packet(&mut buf, &payload);
+
+		let header_len = match len {
+			0...125 => 2,
+			126...65535 => 4,
+			_ => 10,
+		};
+		assert_eq!(buf.len(), header_len + len, "Wrong length encoding for {}", len);
+
+		let (frame, frame_len) = ws::decode_ws_packet(&buf).unwrap().unwrap();
+		assert_eq!(frame_len, buf.len());
+		assert!(frame.final_frame && !frame.masked);
+		assert_eq!(frame.opcode, 0x2);
+		assert!(frame.payload == payload);
+	}
+}
+
+#[test]
+fn partial_frames_are_not_decoded() {
+	let frame = client_frame(true, 0, 0x2, &vec![7u8; 300]);
+
+	for len in 0..frame.len() {
+		assert!(ws::decode_ws_packet(&frame[..len]).unwrap().is_none(), "Decoded a frame from {} bytes", len);
+	}
+
+	let (decoded, len) = ws::decode_ws_packet(&frame).unwrap().unwrap();
+	assert_eq!(len, frame.len());
+	assert!(decoded.masked);
+	assert!(decoded.payload == vec![7u8; 300]);
+}
+
+#[test]
+fn close_reasons_are_truncated_to_fit() {
+	let reason = "é".repeat(100);
+	let mut buf = Vec::new();
+	ws::encode_close_frame(&mut buf, ws::CLOSE_POLICY_VIOLATION, &reason);
+
+	let (frame, _) = ws::decode_ws_packet(&buf).unwrap().unwrap();
+	assert!(frame.payload.len() <= ws::MAX_CONTROL_PAYLOAD_LEN);
+	assert!(::std::str::from_utf8(&frame.payload[2..]).is_ok());
+}
+
+#[test]
+fn malformed_headers_are_errors() {
+	let reserved = client_frame(true, 0, 0x3, b"");
+	assert!(match_enum!(ws::decode_ws_packet(&reserved), Err(ws::FrameError::ReservedOpcode(0x3))));
+
+	// 64 bit lengths can't have the top bit set
+	let mut too_long = vec![0x82, 0x80 | 127, 0x80, 0, 0, 0, 0, 0, 0, 0];
+	too_long.extend_from_slice(&[0; 4]);
+	assert!(match_enum!(ws::decode_ws_packet(&too_long), Err(ws::FrameError::InvalidLength)));
+}
+
+#[test]
+fn malformed_packets_are_errors() {
+	assert!(match_enum!(Packet::parse(&[]), Err(PacketError::Empty)));
+	assert!(match_enum!(Packet::parse(&[0x7F]), Err(PacketError::UnknownType(0x7F))));
+	assert!(match_enum!(Packet::parse(&[0x2, 1]), Err(PacketError::Truncated(0x2))));
+	assert!(match_enum!(Packet::parse(&[0x1, 1]), Err(PacketError::TrailingBytes(0x1))));
+	assert!(match_enum!(Packet::parse(&[0x0, 1, 0xFF]), Err(PacketError::InvalidUtf8(0x0))));
+	assert!(match_enum!(Packet::parse(&[0x0, 5, b'a']), Err(PacketError::Truncated(0x0))));
+	assert!(match_enum!(Packet::parse(&[0x2, 1, 0, 0, 0]), Ok(Packet::AttemptAuthSession(1))));
+}
+
+#[test]
+fn accept_key_matches_rfc_example() {
+	let request = upgrade_request(&[]);
+	let header = ::http::Request::parse(request.as_bytes()).unwrap();
+
+	let mut response = Vec::new();
+	ws::init_websocket_connection(&mut response, &header, &[]).unwrap();
+
+	let response = String::from_utf8(response).unwrap();
+	assert!(response.starts_with("HTTP/1.1 101 "));
+	assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+	assert!(response.contains("Sec-WebSocket-Protocol: wire.v1\r\n"));
+}
+
+// Opening handshake
+
+#[test]
+fn handshake_picks_clients_first_supported_protocol() {
+	let server = TestServer::start();
+	let mut client = TestClient::connect(&server);
+
+	let request = upgrade_request(&[]).replace("Sec-WebSocket-Protocol: wire.v1", "Sec-WebSocket-Protocol: chat, binary, wire.v1");
+	let response = client.request(&request);
+	assert!(response.starts_with("HTTP/1.1 101 "));
+	assert!(response.contains("Sec-WebSocket-Protocol: binary\r\n"));
+}
+
+#[test]
+fn handshake_headers_are_case_insensitive_and_can_repeat() {
+	let server = TestServer::start();
+	let mut client = TestClient::connect(&server);
+
+	let request = upgrade_request(&["sec-websocket-protocol: binary"])
+		.replace("Upgrade: websocket", "upgrade: WebSocket")
+		.replace("Sec-WebSocket-Key", "SEC-WEBSOCKET-KEY")
+		.replace("Sec-WebSocket-Protocol: wire.v1", "Sec-WebSocket-Protocol: chat");
+
+	let response = client.request(&request);
+	assert!(response.starts_with("HTTP/1.1 101 "));
+	assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+	assert!(response.contains("Sec-WebSocket-Protocol: binary\r\n"));
+}
+
+#[test]
+fn handshake_rejects_wrong_version() {
+	let server = TestServer::start();
+	let mut client = TestClient::connect(&server);
+
+	let response = client.request(&upgrade_request(&[]).replace("Version: 13", "Version: 8"));
+	assert!(response.starts_with("HTTP/1.1 426 "));
+	assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+	client.expect_disconnect();
+}
+
+#[test]
+fn handshake_rejects_unsupported_protocols() {
+	let server = TestServer::start();
+	let mut client = TestClient::connect(&server);
+
+	let response = client.request(&upgrade_request(&[]).replace("Protocol: wire.v1", "Protocol: chat, wire.v0"));
+	assert!(response.starts_with("HTTP/1.1 400 "));
+	assert!(response.contains("Sec-WebSocket-Protocol: wire.v1, binary\r\n"));
+	client.expect_disconnect();
+}
+
+#[test]
+fn handshake_checks_the_origin() {
+	let config = Config { allowed_origins: vec![String::from("https://example.com")], .. Config::default() };
+	let server = TestServer::with_config(config);
+
+	let response = TestClient::connect(&server).request(&upgrade_request(&["Origin: https://example.com"]));
+	assert!(response.starts_with("HTTP/1.1 101 "));
+
+	let response = TestClient::connect(&server).request(&upgrade_request(&["Origin: HTTPS://Example.com"]));
+	assert!(response.starts_with("HTTP/1.1 101 "));
+
+	let mut client = TestClient::connect(&server);
+	let response = client.request(&upgrade_request(&["Origin: https://example.com.evil.net"]));
+	assert!(response.starts_with("HTTP/1.1 403 "));
+	client.expect_disconnect();
+
+	// Only browsers send one, so anything else is let through
+	let response = TestClient::connect(&server).request(&upgrade_request(&[]));
+	assert!(response.starts_with("HTTP/1.1 101 "));
+}
+
+#[test]
+fn any_origin_is_allowed_by_default() {
+	let server = TestServer::start();
+	let response = TestClient::connect(&server).request(&upgrade_request(&["Origin: https://anywhere.net"]));
+	assert!(response.starts_with("HTTP/1.1 101 "));
+}
+
+#[test]
+fn handshake_rejects_missing_key() {
+	let server = TestServer::start();
+	let mut client = TestClient::connect(&server);
+
+	let response = client.request(&upgrade_request(&[]).replace("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", ""));
+	assert!(response.starts_with("HTTP/1.1 400 "));
+	client.expect_disconnect();
+}
+
+#[test]
+fn handshake_rejects_oversized_request() {
+	let server = TestServer::start();
+	let mut client = TestClient::connect(&server);
+
+	let padding = format!("X-Padding: {}", "a".repeat(16<<10));
+	let response = client.request(&upgrade_request(&[&padding]));
+	assert!(response.starts_with("HTTP/1.1 431 "));
+	client.expect_disconnect();
+}
+
+#[test]
+fn stalled_handshakes_time_out() {
+	let config = Config { handshake_timeout: Duration::from_millis(100), .. Config::default() };
+	let server = TestServer::with_config(config);
+	let mut client = TestClient::connect(&server);
+
+	// The rest of the request never comes
+	let request = upgrade_request(&[]);
+	client.send_raw(&request.as_bytes()[..request.len() / 2]);
+	let started = Instant::now();
+
+	let response = client.request("");
+	assert!(response.starts_with("HTTP/1.1 408 "), "Expected a timeout: {}", response);
+	assert!(response.contains("Connection: close\r\n"));
+	assert!(started.elapsed() >= Duration::from_millis(50), "Timed out too early");
+	client.expect_disconnect();
+}
+
+#[test]
+fn frames_sent_with_the_handshake_are_kept() {
+	let server = TestServer::start();
+	let mut client = TestClient::connect(&server);
+
+	let mut data = upgrade_request(&[]).into_bytes();
+	data.extend(client_frame(true, 0, 0x9, b"early"));
+	client.send_raw(&data);
+
+	let response = client.request("");
+	assert!(response.starts_with("HTTP/1.1 101 "));
+
+	let pong = client.read_frame().unwrap();
+	assert_eq!(pong.opcode, 0xA);
+	assert_eq!(pong.payload, b"early");
+}
+
+// Data frames
+
+#[test]
+fn all_length_encodings_are_accepted() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	// Debug packets have a one byte header, so these hit each side of the length boundaries
+	for &len in &[1usize, 125, 126, 127, 65535, 65536, 65537, 200000] {
+		let message = "a".repeat(len - 1);
+		client.send_frame(true, 0, 0x2, &debug_payload(&message));
+		client.expect_echo(&message);
+	}
+}
+
+#[test]
+fn frames_split_across_reads_are_reassembled() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	let frame = client_frame(true, 0, 0x2, &debug_payload("byte by byte"));
+	for byte in frame.chunks(1) {
+		client.send_raw(byte);
+		thread::sleep(Duration::from_millis(1));
+	}
+
+	client.expect_echo("byte by byte");
+}
+
+#[test]
+fn coalesced_frames_are_all_decoded() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	let mut data = Vec::new();
+	for message in &["one", "two", "three"] {
+		data.extend(client_frame(true, 0, 0x2, &debug_payload(message)));
+	}
+	client.send_raw(&data);
+
+	client.expect_echo("one");
+	client.expect_echo("two");
+	client.expect_echo("three");
+}
+
+#[test]
+fn replies_sent_in_one_tick_are_batched() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	let mut data = Vec::new();
+	for message in &["one", "two", "three"] {
+		data.extend(client_frame(true, 0, 0x2, &debug_payload(message)));
+	}
+	client.send_raw(&data);
+
+	let frame = client.read_frame().unwrap();
+	assert_eq!(frame.payload[0], BATCH_TYPE);
+	assert_eq!(Packet::parse_message(&frame.payload).unwrap().len(), 3);
+}
+
+#[test]
+fn batches_from_the_client_are_unpacked() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	let mut batch = Batch::new();
+	batch.push(&Packet::Debug(String::from("one")));
+	batch.push(&Packet::RequestDownloadWorld);
+	batch.push(&Packet::Debug(String::from("two")));
+
+	let mut payload = Vec::new();
+	batch.write(&mut payload);
+	client.send_frame(true, 0, 0x2, &payload);
+
+	assert!(match_enum!(server.next_packet(), Packet::Debug(_)));
+	assert!(match_enum!(server.next_packet(), Packet::RequestDownloadWorld));
+	assert!(match_enum!(server.next_packet(), Packet::Debug(_)));
+
+	client.expect_echo("one");
+	client.expect_echo("two");
+}
+
+#[test]
+fn batches_with_an_invalid_packet_are_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	client.send_frame(true, 0, 0x2, &[BATCH_TYPE, 1, 0x1, 1, 0x7F]);
+	client.expect_close(ws::CLOSE_UNSUPPORTED_DATA);
+}
+
+#[test]
+fn unmasked_frames_are_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	let mut frame = Vec::new();
+	ws::encode_ws_frame(&mut frame, 0x9, b"unmasked");
+	client.send_raw(&frame);
+	client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+}
+
+#[test]
+fn reserved_bits_are_rejected() {
+	// RSV1 is only allowed once permessage-deflate has been negotiated
+	for &rsv in &[0b100, 0b010, 0b001] {
+		let server = TestServer::start();
+		let mut client = TestClient::upgrade(&server);
+
+		client.send_frame(true, rsv, 0x2, &debug_payload("reserved"));
+		client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+	}
+}
+
+#[test]
+fn reserved_opcodes_are_rejected() {
+	for opcode in (0x3..0x8).chain(0xB..0x10) {
+		let server = TestServer::start();
+		let mut client = TestClient::upgrade(&server);
+
+		client.send_frame(true, 0, opcode, b"reserved");
+		client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+	}
+}
+
+#[test]
+fn invalid_packets_are_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	client.send_frame(true, 0, 0x2, &[0x7F, 1, 2, 3]);
+	client.expect_close(ws::CLOSE_UNSUPPORTED_DATA);
+}
+
+#[test]
+fn empty_and_truncated_packets_are_rejected() {
+	for payload in &[&[][..], &[0x2, 1][..]] {
+		let server = TestServer::start();
+		let mut client = TestClient::upgrade(&server);
+
+		client.send_frame(true, 0, 0x2, payload);
+		client.expect_close(ws::CLOSE_UNSUPPORTED_DATA);
+	}
+}
+
+#[test]
+fn oversized_64_bit_lengths_are_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_raw(&[0x82, 0x80 | 127, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x37, 0xfa, 0x21, 0x3d]);
+	client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+}
+
+#[test]
+fn mismatched_versions_are_told_to_reload() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_packet(&Packet::Hello(PROTOCOL_VERSION + 1, String::from("future")));
+
+	let frame = client.read_frame().unwrap();
+	assert!(match_enum!(Packet::parse(&frame.payload), Ok(Packet::VersionMismatch(PROTOCOL_VERSION))));
+	client.expect_close(ws::CLOSE_POLICY_VIOLATION);
+}
+
+#[test]
+fn clients_that_skip_hello_are_told_to_reload() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	// What a client from before the version handshake opens with
+	client.send_packet(&Packet::AttemptAuthSession(123));
+
+	let frame = client.read_frame().unwrap();
+	assert!(match_enum!(Packet::parse(&frame.payload), Ok(Packet::VersionMismatch(PROTOCOL_VERSION))));
+	client.expect_close(ws::CLOSE_POLICY_VIOLATION);
+}
+
+#[test]
+fn text_can_be_sent_before_hello() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(true, 0, 0x1, b"before hello");
+	client.expect_echo("before hello");
+
+	// The connection is still waiting for its hello
+	client.send_packet(&Packet::Hello(PROTOCOL_VERSION, String::from("test")));
+	assert!(match_enum!(client.read_packet(), Some(Packet::ServerHello(PROTOCOL_VERSION))));
+}
+
+#[test]
+fn oversized_messages_are_rejected() {
+	let config = Config { max_message_size: 1024, .. Config::default() };
+	let server = TestServer::with_config(config);
+	let mut client = TestClient::authenticated(&server);
+
+	// A type byte, a two byte length and the text only just fit
+	client.send_frame(true, 0, 0x2, &debug_payload(&"a".repeat(1021)));
+	client.expect_echo(&"a".repeat(1021));
+
+	// Rejected from the header alone, without waiting for the payload
+	let frame = client_frame(true, 0, 0x2, &vec![0u8; 2048]);
+	client.send_raw(&frame[..16]);
+	client.expect_close(ws::CLOSE_MESSAGE_TOO_BIG);
+}
+
+#[test]
+fn oversized_fragmented_messages_are_rejected() {
+	let config = Config { max_message_size: 1024, .. Config::default() };
+	let server = TestServer::with_config(config);
+	let mut client = TestClient::authenticated(&server);
+
+	client.send_frame(false, 0, 0x2, &vec![0u8; 1000]);
+	client.send_frame(false, 0, 0x0, &vec![0u8; 1000]);
+	client.expect_close(ws::CLOSE_MESSAGE_TOO_BIG);
+}
+
+// Fragmentation
+
+#[test]
+fn fragmented_messages_are_reassembled() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	let payload = debug_payload("fragmented message");
+	client.send_frame(false, 0, 0x2, &payload[..5]);
+	client.send_frame(false, 0, 0x0, &payload[5..10]);
+	client.send_frame(false, 0, 0x0, &[]);
+	client.send_frame(true, 0, 0x0, &payload[10..]);
+
+	client.expect_echo("fragmented message");
+}
+
+#[test]
+fn control_frames_can_interrupt_fragmented_messages() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	let payload = debug_payload("interrupted");
+	client.send_frame(false, 0, 0x2, &payload[..4]);
+	client.send_frame(true, 0, 0x9, b"ping");
+	client.send_frame(true, 0, 0xA, b"unsolicited pong");
+	client.send_frame(true, 0, 0x0, &payload[4..]);
+
+	let pong = client.read_frame().unwrap();
+	assert_eq!(pong.opcode, 0xA);
+	assert_eq!(pong.payload, b"ping");
+
+	client.expect_echo("interrupted");
+}
+
+#[test]
+fn close_can_interrupt_fragmented_messages() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	client.send_frame(false, 0, 0x2, &debug_payload("never finished"));
+	client.send_frame(true, 0, 0x8, &[0x03, 0xE8]);
+	client.expect_close(1000);
+	client.expect_disconnect();
+}
+
+#[test]
+fn continuation_without_a_message_is_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(true, 0, 0x0, b"orphan");
+	client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+}
+
+#[test]
+fn new_message_during_fragmented_message_is_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(false, 0, 0x2, b"first");
+	client.send_frame(true, 0, 0x2, b"second");
+	client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+}
+
+// Control frames
+
+#[test]
+fn pings_are_answered_with_matching_pongs() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	for payload in &[vec![], b"hello".to_vec(), vec![0xFE; 125]] {
+		client.send_frame(true, 0, 0x9, payload);
+
+		let pong = client.read_frame().unwrap();
+		assert_eq!(pong.opcode, 0xA);
+		assert!(pong.final_frame);
+		assert_eq!(&pong.payload, payload);
+	}
+}
+
+#[test]
+fn oversized_control_frames_are_rejected() {
+	for &opcode in &[0x8, 0x9, 0xA] {
+		let server = TestServer::start();
+		let mut client = TestClient::upgrade(&server);
+
+		let mut payload = vec![0u8; 126];
+		payload[0] = 0x03;
+		payload[1] = 0xE8;
+
+		client.send_frame(true, 0, opcode, &payload);
+		client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+	}
+}
+
+#[test]
+fn fragmented_control_frames_are_rejected() {
+	for &opcode in &[0x8, 0x9, 0xA] {
+		let server = TestServer::start();
+		let mut client = TestClient::upgrade(&server);
+
+		client.send_frame(false, 0, opcode, &[0x03, 0xE8]);
+		client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+	}
+}
+
+// Closing handshake
+
+#[test]
+fn close_is_echoed() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	let mut payload = vec![0x0F, 0xA0]; // 4000
+	payload.extend_from_slice(b"going away");
+	client.send_frame(true, 0, 0x8, &payload);
+
+	client.expect_close(4000);
+	client.expect_disconnect();
+}
+
+#[test]
+fn empty_close_is_echoed_empty() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(true, 0, 0x8, &[]);
+
+	let frame = client.read_frame().unwrap();
+	assert_eq!(frame.opcode, 0x8);
+	assert!(frame.payload.is_empty());
+	client.expect_disconnect();
+}
+
+#[test]
+fn invalid_close_statuses_are_rejected() {
+	for &status in &[0u16, 999, 1004, 1005, 1006, 1015, 1100, 2999, 5000] {
+		let server = TestServer::start();
+		let mut client = TestClient::upgrade(&server);
+
+		client.send_frame(true, 0, 0x8, &[(status >> 8) as u8, status as u8]);
+		client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+	}
+}
+
+#[test]
+fn one_byte_close_is_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(true, 0, 0x8, &[0x03]);
+	client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+}
+
+#[test]
+fn close_with_invalid_utf8_reason_is_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(true, 0, 0x8, &[0x03, 0xE8, 0xCE, 0xBA, 0xE1, 0xBD]);
+	client.expect_close(ws::CLOSE_INVALID_PAYLOAD);
+}
+
+#[test]
+fn server_initiated_close_waits_for_echo() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(true, 0, 0x0, b"orphan");
+	client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+
+	client.send_frame(true, 0, 0x8, &[0x03, 0xEA]);
+	client.expect_disconnect();
+}
+
+// Heartbeats
+
+#[test]
+fn idle_connections_are_pinged() {
+	let config = Config { ping_interval: Duration::from_millis(50), pong_timeout: Duration::from_millis(200), .. Config::default() };
+	let server = TestServer::with_config(config);
+	let mut client = TestClient::upgrade(&server);
+
+	// Answered pings are followed by another, an interval later
+	for _ in 0..3 {
+		let ping = client.read_frame().expect("Connection dropped before a ping");
+		assert_eq!(ping.opcode, 0x9);
+		client.send_frame(true, 0, 0xA, &ping.payload);
+	}
+}
+
+#[test]
+fn unanswered_pings_time_out() {
+	let config = Config { ping_interval: Duration::from_millis(50), pong_timeout: Duration::from_millis(200), .. Config::default() };
+	let server = TestServer::with_config(config);
+	let mut client = TestClient::upgrade(&server);
+
+	let ping = client.read_frame().expect("Connection dropped before a ping");
+	assert_eq!(ping.opcode, 0x9);
+	let pinged = Instant::now();
+
+	// A pong for some other ping doesn't count
+	client.send_frame(true, 0, 0xA, b"unsolicited");
+
+	client.expect_disconnect();
+	assert!(pinged.elapsed() >= Duration::from_millis(150), "Dropped before the timeout");
+}
+
+// permessage-deflate
+
+// Upgrades with permessage-deflate, which is accepted with the server's defaults
+fn upgrade_compressed(server: &TestServer) -> TestClient {
+	let mut client = TestClient::connect(server);
+	let response = client.request(&upgrade_request(&["Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits"]));
+	assert!(response.starts_with("HTTP/1.1 101 "), "Upgrade refused: {}", response);
+	assert!(response.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"));
+	client
+}
+
+#[test]
+fn compressed_messages_are_exchanged() {
+	let server = TestServer::start();
+	let mut client = upgrade_compressed(&server);
+
+	let mut deflater = Deflater::new(false);
+	let mut inflater = Inflater::new(false);
+
+	// Small messages aren't worth compressing, so both ends can mix them in
+	client.send_packet(&Packet::Hello(PROTOCOL_VERSION, String::from("test")));
+	let frame = client.read_frame().unwrap();
+	assert_eq!(frame.rsv, 0);
+	assert!(match_enum!(Packet::parse(&frame.payload), Ok(Packet::ServerHello(PROTOCOL_VERSION))));
+
+	// Only the first frame of a fragmented message has RSV1 set
+	let mut auth = Vec::new();
+	Packet::AttemptAuthSession(123).write(&mut auth);
+	let compressed = deflater.compress_message(&auth);
+	client.send_frame(false, 0b100, 0x2, &compressed[..2]);
+	client.send_frame(true, 0, 0x0, &compressed[2..]);
+
+	let frame = client.read_frame().unwrap();
+	assert_eq!(frame.payload[0], Packet::AuthSuccessful(0).get_type());
+
+	// Twice, so the second message in each direction refers back to the first
+	let message = "compressed ".repeat(20);
+	for &opcode in &[0x2, 0x1, 0x2] {
+		let payload = if opcode == 0x1 { message.as_bytes().to_vec() } else { debug_payload(&message) };
+		client.send_frame(true, 0b100, opcode, &deflater.compress_message(&payload));
+
+		let frame = client.read_frame().unwrap();
+		assert_eq!(frame.opcode, 0x2);
+		assert_eq!(frame.rsv, 0b100);
+
+		let packets = Packet::parse_message(&inflater.decompress_message(&frame.payload, 1<<20).unwrap()).unwrap();
+		assert_eq!(packets.len(), 1);
+		match packets[0] {
+			Packet::Debug(ref echo) => assert!(echo == &message, "Echo doesn't match"),
+			_ => panic!("Expected a debug packet"),
+		}
+	}
+}
+
+#[test]
+fn compressed_control_frames_are_rejected() {
+	for &(rsv, opcode) in &[(0b100, 0x9), (0b100, 0x0), (0b010, 0x2)] {
+		let server = TestServer::start();
+		let mut client = upgrade_compressed(&server);
+
+		client.send_frame(true, rsv, opcode, &Deflater::new(false).compress_message(b"ping"));
+		client.expect_close(ws::CLOSE_PROTOCOL_ERROR);
+	}
+}
+
+#[test]
+fn invalid_compressed_data_is_rejected() {
+	let server = TestServer::start();
+	let mut client = upgrade_compressed(&server);
+
+	client.send_frame(true, 0b100, 0x2, &[0xff, 0xff, 0xff]);
+	client.expect_close(ws::CLOSE_INVALID_PAYLOAD);
+}
+
+// Text
+
+#[test]
+fn text_is_treated_as_debug_messages() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	client.send_frame(true, 0, 0x1, "κόσμε".as_bytes());
+	client.expect_echo("κόσμε");
+
+	match server.next_packet() {
+		Packet::Debug(ref s) => assert_eq!(s, "κόσμε"),
+		_ => panic!("Expected a debug packet"),
+	}
+}
+
+#[test]
+fn text_is_accepted_before_auth() {
+	let server = TestServer::start();
+	let mut client = TestClient::greeted(&server);
+
+	client.send_frame(true, 0, 0x1, b"status");
+	client.expect_echo("status");
+	assert!(match_enum!(server.next_packet(), Packet::Debug(_)));
+}
+
+#[test]
+fn text_split_mid_character_is_accepted() {
+	let server = TestServer::start();
+	let mut client = TestClient::authenticated(&server);
+
+	let text = "κόσμε".as_bytes();
+	for (i, byte) in text.iter().enumerate() {
+		let opcode = if i == 0 { 0x1 } else { 0x0 };
+		client.send_frame(i == text.len() - 1, 0, opcode, &[*byte]);
+	}
+
+	client.expect_echo("κόσμε");
+}
+
+#[test]
+fn invalid_text_is_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(true, 0, 0x1, &[0xCE, 0xBA, 0xE1, 0xBD, 0xB9, 0xCF, 0x83, 0xCE, 0xBC, 0xCE, 0xB5, 0xED, 0xA0, 0x80]);
+	client.expect_close(ws::CLOSE_INVALID_PAYLOAD);
+}
+
+#[test]
+fn invalid_text_is_rejected_before_the_message_ends() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(false, 0, 0x1, &[b'o', b'k', 0xFF]);
+	client.expect_close(ws::CLOSE_INVALID_PAYLOAD);
+}
+
+#[test]
+fn text_ending_mid_character_is_rejected() {
+	let server = TestServer::start();
+	let mut client = TestClient::upgrade(&server);
+
+	client.send_frame(false, 0, 0x1, b"abc");
+	client.send_frame(true, 0, 0x0, &[0xE2, 0x82]);
+	client.expect_close(ws::CLOSE_INVALID_PAYLOAD);
+}
+
+// Backpressure
+
+#[test]
+fn slow_consumers_lose_droppable_packets_then_the_connection() {
+	let config = Config { send_queue_high_water: 64<<10, send_queue_limit: 1<<20, .. Config::default() };
+	let server = TestServer::with_config(config);
+	let mut client = TestClient::authenticated(&server);
+
+	// Far more echoes than the socket buffers can hold, while nothing is read
+	let count = 256;
+	let padding = "a".repeat(64<<10);
+	for i in 0..count {
+		client.send_frame(true, 0, 0x2, &debug_payload(&format!("{:04} {}", i, padding)));
+	}
+
+	// Pongs can't be dropped, and go out as soon as the ping is read, so this arrives after all but
+	// perhaps the last few echoes
+	client.send_frame(true, 0, 0x9, b"sent");
+
+	let mut echoes = 0;
+	loop {
+		let frame = client.read_frame().expect("Connection closed while draining");
+		match frame.opcode {
+			0x2 => echoes += Packet::parse_message(&frame.payload).unwrap().len(),
+			0xA if frame.payload == b"sent" => break,
+			_ => {}
+		}
+	}
+
+	assert!(echoes > 0 && echoes < count, "{} of {} echoes arrived", echoes, count);
+
+	// Pongs pile up until there are too many to keep, and the client is cut off
+	let pings = (0..1000).flat_map(|_| client_frame(true, 0, 0x9, &[0xAB; 125])).collect::<Vec<_>>();
+	let mut sent = 0;
+	while sent < 256<<20 && client.stream.write_all(&pings).is_ok() {
+		sent += pings.len();
+	}
+
+	assert!(sent < 256<<20, "Never disconnected");
+
+	// Without a close frame, since the client isn't listening
+	while let Some(frame) = client.read_frame() {
+		assert!(frame.opcode != 0x8, "Slow consumers aren't sent a close");
+	}
+}
@@ -1,32 +1,78 @@
-use std::net::TcpStream;
-use std::io::{Write, Read};
-use common::Packet;
+use std::io::{self, Write, Read};
+use std::collections::VecDeque;
+use std::time::{Instant, Duration};
+use std::str;
+use std::mem;
+use std::net;
+use mio::{Poll, Token, Ready, PollOpt};
+use common::{Packet, Batch, PROTOCOL_VERSION};
+use common::wire;
+use config::Config;
+use deflate::{self, Deflater, Inflater};
+use http;
+use tls::{self, TlsAcceptor, BlockingStream};
 use ws;
 
 pub type ConnectionID = u32;
 
 pub const MAX_FAILED_AUTH_ATTEMPTS: i32 = 100;
+pub const CLOSE_TIMEOUT_MS: u64 = 5000;
+
+// Batches are sent early once they get this big, so that every message fits in one read on the client
+pub const MAX_BATCH_LEN: usize = 4<<10;
 
 #[derive(Debug)]
 pub enum ConnectionState {
+	Handshaking{since: Instant},
+	PlainHttp,
+	AwaitingHello,
 	NoAuth,
 	AttemptingAuth{token: u32, waiting: bool},
 	AwaitingNewSession,
 	NewSessionRequested,
 	Ready,
+	Closing{since: Instant},
 	AwaitingDeletion,
 }
 
 pub struct Connection {
-	pub stream: TcpStream,
+	pub stream: tls::Stream,
+	pub decoder: ws::FrameDecoder,
+	pub deflater: Option<Deflater>,
 	pub state: ConnectionState,
 	pub failed_auth_attempts: i32,
 
 	pub session_id: Option<u32>,
 	pub id: ConnectionID,
+
+	// The initial request, until it's been received in full
+	handshake_buffer: Vec<u8>,
+
+	// Why the connection was or is being closed, None if it's still open
+	pub close_reason: Option<ws::CloseReason>,
+
+	last_ping: Instant,
+	ping_count: u32,
+	awaiting_pong: bool,
+
+	// Bytes that couldn't be written without blocking yet
+	outbound: Vec<u8>,
+	batch: Batch,
+	send_queue_high_water: usize,
+	send_queue_limit: usize,
+	backlogged: bool,
+	pub dropped_packets: u32,
 }
 
 impl Connection {
+	pub fn is_handshaking(&self) -> bool {
+		match_enum!(self.state, ConnectionState::Handshaking{..})
+	}
+
+	pub fn is_plain_http(&self) -> bool {
+		match_enum!(self.state, ConnectionState::PlainHttp)
+	}
+
 	pub fn is_awaiting_new_session(&self) -> bool {
 		match_enum!(self.state, ConnectionState::AwaitingNewSession)
 	}
@@ -39,39 +85,265 @@ impl Connection {
 		match_enum!(self.state, ConnectionState::Ready)
 	}
 
-	pub fn send_payload(&mut self, mut packet_buffer: &mut [u8], payload: &[u8]) {
-		let packet = ws::encode_ws_packet(&mut packet_buffer, &payload);
-		let _ = self.stream.write_all(&packet);
+	pub fn is_closing(&self) -> bool {
+		match_enum!(self.state, ConnectionState::Closing{..})
+	}
+
+	// Heartbeats start once the connection is upgraded and stop once it starts closing
+	fn is_heartbeat_enabled(&self) -> bool {
+		!self.is_handshaking() && !self.is_plain_http() && !self.is_closing() && !self.is_awaiting_deletion()
+	}
+
+	fn queued_bytes(&self) -> usize {
+		self.outbound.len()
+	}
+
+	// Packets go out together at the end of the tick, unless the batch gets too big to wait.
+	// Droppable packets are discarded rather than queued if the client isn't keeping up
+	fn queue_packet(&mut self, packet: &[u8], droppable: bool) {
+		// Nothing but a close can be sent once a close has been sent
+		if self.is_closing() || self.is_awaiting_deletion() { return }
+
+		if droppable && self.backlogged {
+			self.dropped_packets += 1;
+			return;
+		}
+
+		if self.batch.len() + packet.len() > MAX_BATCH_LEN {
+			self.flush_batch();
+		}
+
+		self.batch.push_encoded(packet);
+	}
+
+	pub fn send_packet(&mut self, p: &Packet) {
+		let mut payload = Vec::new();
+		p.write(&mut payload);
+		self.queue_packet(&payload, p.is_droppable());
+	}
+
+	// Sends everything batched so far as one message
+	pub fn flush_batch(&mut self) {
+		if self.batch.is_empty() { return }
+
+		let mut payload = Vec::new();
+		self.batch.write(&mut payload);
+
+		let mut packet_buffer = Vec::new();
+
+		match self.deflater {
+			Some(ref mut deflater) if payload.len() >= deflate::MIN_COMPRESS_LEN => {
+				let compressed = deflater.compress_message(&payload);
+				ws::encode_compressed_ws_packet(&mut packet_buffer, &compressed);
+			}
+
+			_ => ws::encode_ws_packet(&mut packet_buffer, &payload),
+		}
+
+		self.queue_bytes(&packet_buffer);
+	}
+
+	pub fn send_control_frame(&mut self, opcode: u8, payload: &[u8]) {
+		let mut packet_buffer = Vec::with_capacity(2 + ws::MAX_CONTROL_PAYLOAD_LEN);
+		ws::encode_ws_frame(&mut packet_buffer, opcode, &payload);
+		self.queue_bytes(&packet_buffer);
+	}
+
+	fn send_close_frame(&mut self, status: u16, reason: &str) {
+		let mut packet_buffer = Vec::with_capacity(2 + ws::MAX_CONTROL_PAYLOAD_LEN);
+		ws::encode_close_frame(&mut packet_buffer, status, reason);
+		self.queue_bytes(&packet_buffer);
+	}
+
+	fn queue_bytes(&mut self, data: &[u8]) {
+		self.outbound.extend_from_slice(data);
+		self.try_write();
+
+		if self.queued_bytes() > self.send_queue_limit {
+			println!("Send queue limit exceeded ({}): {} bytes", self.id, self.queued_bytes());
+			self.outbound.clear();
+			self.abort("Slow consumer");
+
+		} else if !self.backlogged && self.queued_bytes() > self.send_queue_high_water {
+			println!("Send queue over high water mark ({}): {} bytes", self.id, self.queued_bytes());
+			self.backlogged = true;
+		}
+	}
+
+	// Writes as much of the send queue as the socket will take without blocking
+	pub fn try_write(&mut self) {
+		let mut written = 0;
+
+		while written < self.outbound.len() {
+			match self.stream.write(&self.outbound[written..]) {
+				Ok(0) => {
+					self.abort("Connection lost");
+					break
+				}
+
+				Ok(len) => written += len,
+
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => {
+					self.abort(&format!("Write failed: {}", e));
+					break
+				}
+			}
+		}
+
+		self.outbound.drain(..written);
+
+		// TLS can have records of its own waiting to go out, like handshake messages
+		match self.stream.flush() {
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+			Err(e) => self.abort(&format!("Write failed: {}", e)),
+			Ok(()) => {},
+		}
+
+		if self.backlogged && self.queued_bytes() <= self.send_queue_high_water {
+			println!("Send queue drained ({}): {} droppable packets dropped", self.id, self.dropped_packets);
+			self.backlogged = false;
+			self.dropped_packets = 0;
+		}
+	}
+
+	// Starts the closing handshake. The connection sticks around until the client
+	// echoes the close, or CLOSE_TIMEOUT_MS passes
+	pub fn close(&mut self, status: u16, reason: &str) {
+		if self.is_closing() || self.is_awaiting_deletion() { return }
+
+		// Whatever was sent before closing still goes first
+		self.flush_batch();
+		self.send_close_frame(status, reason);
+
+		self.close_reason = Some(ws::CloseReason::new(status, reason));
+		self.state = ConnectionState::Closing{since: Instant::now()};
+	}
+
+	// Either the client is starting the closing handshake and we need to echo it,
+	// or it's responding to one we started
+	pub fn notify_close(&mut self, reason: ws::CloseReason) {
+		if !self.is_closing() {
+			self.flush_batch();
+			self.send_close_frame(reason.status, "");
+			self.close_reason = Some(reason);
+		}
+
+		self.state = ConnectionState::AwaitingDeletion;
+	}
+
+	// Responds to an upgrade request that can't be accepted, and drops the connection
+	fn reject_handshake(&mut self, status_line: &str, headers: &[(&str, &str)], reason: &str) {
+		let mut response = Vec::new();
+		ws::write_handshake_error(&mut response, status_line, headers, reason);
+		self.queue_bytes(&response);
+
+		self.abort(reason);
+	}
+
+	// Drops the connection without a closing handshake, for when the client can't be expected to respond
+	pub fn abort(&mut self, reason: &str) {
+		if self.close_reason.is_none() {
+			self.close_reason = Some(ws::CloseReason::new(ws::CLOSE_ABNORMAL, reason));
+		}
+
+		self.state = ConnectionState::AwaitingDeletion;
+	}
+
+	pub fn send_ping(&mut self) {
+		self.ping_count = self.ping_count.wrapping_add(1);
+		self.last_ping = Instant::now();
+		self.awaiting_pong = true;
+
+		let mut payload = Vec::new();
+		wire::Writer::new(&mut payload).write_u32(self.ping_count);
+		self.send_control_frame(0x9, &payload);
+	}
+
+	pub fn notify_pong(&mut self, payload: &[u8]) {
+		// Unsolicited pongs and pongs for old pings are allowed, but don't count
+		let mut reader = wire::Reader::new(payload);
+		if reader.read_u32() == Ok(self.ping_count) && reader.finish().is_ok() {
+			self.awaiting_pong = false;
+		}
 	}
 }
 
 pub struct ConnectionManager {
 	pub connections: Vec<Connection>,
+	incoming_packets: VecDeque<(ConnectionID, Packet)>,
 
+	config: Config,
+	tls: Option<TlsAcceptor>,
 	next_id: ConnectionID,
 }
 
 impl ConnectionManager {
-	pub fn new() -> Self {
+	pub fn new(config: Config, tls: Option<TlsAcceptor>) -> Self {
 		ConnectionManager{
 			connections: Vec::new(),
+			incoming_packets: VecDeque::new(),
 
+			config,
+			tls,
 			next_id: 1,
 		}
 	}
 
-	pub fn register_connection(&mut self, stream: TcpStream) {
-		stream.set_nonblocking(true).expect("Set nonblock failed");
+	pub fn register_connection(&mut self, poll: &Poll, socket: net::TcpStream) {
+		let socket = match tls::Socket::new(socket) {
+			Ok(socket) => socket,
+			Err(e) => {
+				println!("Failed to accept connection: {}", e);
+				return;
+			}
+		};
+
+		let stream = match self.tls {
+			Some(ref acceptor) => match acceptor.accept(socket) {
+				Ok(stream) => stream,
+				Err(e) => {
+					println!("Failed to accept connection: {}", e);
+					return;
+				}
+			},
+
+			None => tls::Stream::Plain(socket),
+		};
+
+		let token = Token(self.next_id as usize);
+		if let Err(e) = poll.register(stream.socket(), token, Ready::readable() | Ready::writable(), PollOpt::edge()) {
+			println!("Failed to register connection: {}", e);
+			return;
+		}
 
 		println!("Connection ({})", self.next_id);
 
 		self.connections.push(Connection {
 			stream,
-			state: ConnectionState::NoAuth,
+			decoder: ws::FrameDecoder::new(self.config.max_message_size, None),
+			deflater: None,
+			state: ConnectionState::Handshaking{since: Instant::now()},
 			failed_auth_attempts: 0,
 
 			session_id: None,
 			id: self.next_id,
+
+			handshake_buffer: Vec::new(),
+
+			close_reason: None,
+
+			last_ping: Instant::now(),
+			ping_count: 0,
+			awaiting_pong: false,
+
+			outbound: Vec::new(),
+			batch: Batch::new(),
+			send_queue_high_water: self.config.send_queue_high_water,
+			send_queue_limit: self.config.send_queue_limit,
+			backlogged: false,
+			dropped_packets: 0,
 		});
 
 		self.next_id += 1;
@@ -79,6 +351,8 @@ impl ConnectionManager {
 
 	pub fn imbue_session(&mut self, id: ConnectionID, token: u32) -> bool {
 		if let Some(ref mut con) = self.connections.iter_mut().find(|c| c.id == id) {
+			if con.is_closing() || con.is_awaiting_deletion() { return false }
+
 			assert!(con.session_id.is_none());
 
 			con.session_id = Some(token);
@@ -111,23 +385,99 @@ impl ConnectionManager {
 		use self::ConnectionState::*;
 
 		if let Some(ref mut con) = self.connections.iter_mut().find(|c| c.id == id) {
+			if con.is_closing() || con.is_awaiting_deletion() { return }
+
 			con.failed_auth_attempts += 1;
 
-			con.state = match con.state {
+			match con.state {
 				// TODO: Temp ban??
 				AttemptingAuth{waiting: true, ..} =>
-					if con.failed_auth_attempts > MAX_FAILED_AUTH_ATTEMPTS { AwaitingDeletion }
-					else { NoAuth },
+					if con.failed_auth_attempts > MAX_FAILED_AUTH_ATTEMPTS {
+						con.close(ws::CLOSE_POLICY_VIOLATION, "Too many failed auth attempts");
+					} else {
+						con.state = NoAuth;
+					},
 
 				_ => {
 					println!("notify_auth_fail called on connection not waiting for auth - closing...");
-					AwaitingDeletion
+					con.close(ws::CLOSE_INTERNAL_ERROR, "Unexpected auth failure");
 				},
-			};
+			}
 		}
 	}
 
+	pub fn send_heartbeats(&mut self) {
+		let now = Instant::now();
+
+		for con in self.connections.iter_mut().filter(|c| c.is_heartbeat_enabled()) {
+			let since_last_ping = now.duration_since(con.last_ping);
+
+			if con.awaiting_pong {
+				if since_last_ping > self.config.pong_timeout {
+					con.abort("Ping timeout");
+				}
+
+			} else if since_last_ping >= self.config.ping_interval {
+				con.send_ping();
+			}
+		}
+	}
+
+	// How long until a ping, or a timeout needs checking.
+	// The event loop can sleep at most this long
+	pub fn next_timeout(&self) -> Option<Duration> {
+		let close_timeout = Duration::from_millis(CLOSE_TIMEOUT_MS);
+
+		let deadline = self.connections.iter()
+			.filter_map(|con| match con.state {
+				ConnectionState::Handshaking{since} => Some(since + self.config.handshake_timeout),
+				ConnectionState::Closing{since} => Some(since + close_timeout),
+				_ if !con.is_heartbeat_enabled() => None,
+				_ if con.awaiting_pong => Some(con.last_ping + self.config.pong_timeout),
+				_ => Some(con.last_ping + self.config.ping_interval),
+			})
+			.min();
+
+		let now = Instant::now();
+		deadline.map(|d| if d > now { d - now } else { Duration::from_millis(0) })
+	}
+
 	pub fn flush(&mut self) {
+		let close_timeout = Duration::from_millis(CLOSE_TIMEOUT_MS);
+
+		for con in self.connections.iter_mut() {
+			con.flush_batch();
+
+			match con.state {
+				ConnectionState::Handshaking{since} => if since.elapsed() > self.config.handshake_timeout {
+					println!("Opening handshake timed out ({})", con.id);
+					con.reject_handshake("HTTP/1.1 408 Request Timeout", &[], "Handshake timed out");
+				},
+
+				ConnectionState::Closing{since} => if since.elapsed() > close_timeout {
+					println!("Closing handshake timed out ({})", con.id);
+					con.state = ConnectionState::AwaitingDeletion;
+				},
+
+				_ => {}
+			}
+		}
+
+		for con in self.connections.iter_mut().filter(|c| c.is_awaiting_deletion()) {
+			// Last chance to get out any close frame or error response
+			con.try_write();
+
+			match con.close_reason {
+				Some(ref reason) => println!("Disconnection ({}): {}", con.id, reason),
+				None => println!("Disconnection ({}): {}", con.id, ws::CLOSE_ABNORMAL),
+			}
+
+			// Whatever the socket wouldn't take is lost with it
+			if con.queued_bytes() > 0 {
+				println!("Disconnection ({}): {} bytes never sent", con.id, con.queued_bytes());
+			}
+		}
+
 		self.connections.retain(|x| !x.is_awaiting_deletion());
 	}
 
@@ -141,6 +491,25 @@ impl ConnectionManager {
 			})
 	}
 
+	// Connections that turned out not to be websockets are taken out of the event loop,
+	// and returned along with the request so they can be handed to the file server
+	pub fn poll_http_requests(&mut self, poll: &Poll) -> Option<(Box<dyn BlockingStream>, Vec<u8>)> {
+		while let Some(pos) = self.connections.iter().position(|c| c.is_plain_http()) {
+			let con = self.connections.remove(pos);
+
+			if let Err(e) = poll.deregister(con.stream.socket()) {
+				println!("Failed to deregister connection ({}): {}", con.id, e);
+			}
+
+			match con.stream.into_blocking(Some(self.config.http_idle_timeout)) {
+				Ok(stream) => return Some((stream, con.handshake_buffer)),
+				Err(e) => println!("Failed to hand off connection ({}): {}", con.id, e),
+			}
+		}
+
+		None
+	}
+
 	pub fn poll_auth_attempts(&mut self) -> Option<(ConnectionID, u32)> {
 		self.connections.iter_mut()
 			.filter(|c| match_enum!(c.state, ConnectionState::AttemptingAuth{waiting: false, ..}))
@@ -159,12 +528,7 @@ impl ConnectionManager {
 		if let Some(ref mut con) = self.connections.iter_mut().find(|c| c.id == id) {
 			if !p.is_valid_from_server() { return false }
 
-			let mut payload = [0u8; 4<<10];
-			let mut packet_buffer = [0u8; 4<<10];
-			let len = p.write(&mut payload);
-
-			con.send_payload(&mut packet_buffer, &payload[..len]);
-
+			con.send_packet(p);
 			true
 		} else {
 			false
@@ -172,55 +536,214 @@ impl ConnectionManager {
 	}
 
 	pub fn broadcast_to_authed(&mut self, p: &Packet) {
-		let mut payload = [0u8; 4<<10];
-		let mut packet_buffer = [0u8; 4<<10];
-		let len = p.write(&mut payload);
+		let mut payload = Vec::new();
+		p.write(&mut payload);
 
 		for con in self.connections.iter_mut().filter(|c| c.is_ready()) {
-			con.send_payload(&mut packet_buffer, &payload[..len]);
+			con.queue_packet(&payload, p.is_droppable());
 		}
 	}
 
-	pub fn try_read(&mut self, mut read_buffer: &mut [u8]) -> Option<(ConnectionID, Packet)> {
-		for mut con in &mut self.connections {
-			let res = con.stream.read(&mut read_buffer);
-			let length = match res {
+	// Only well formed packets end up here. Anything malformed closes the connection it came from
+	pub fn try_read(&mut self) -> Option<(ConnectionID, Packet)> {
+		self.incoming_packets.pop_front()
+	}
+
+	// Reads everything available from a connection, decoding whatever can be decoded
+	pub fn handle_readable(&mut self, id: ConnectionID, read_buffer: &mut [u8]) {
+		let con = match self.connections.iter_mut().find(|c| c.id == id) {
+			Some(c) => c, None => return
+		};
+
+		// Once a connection turns out to be plain http, whatever's left is for the file server to read
+		while !con.is_awaiting_deletion() && !con.is_plain_http() {
+			let length = match con.stream.read(read_buffer) {
+				Ok(0) => {
+					println!("Zero length packet ({})", con.id);
+					con.abort("Connection lost");
+					break;
+				}
+
 				Ok(length) => length,
-				Err(_) => continue,
+
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => {
+					con.abort(&format!("Read failed: {}", e));
+					break;
+				}
 			};
 
-			if length == 0 {
-				println!("Zero length packet ({})", con.id);
-				con.state = ConnectionState::AwaitingDeletion;
-				continue;
+			if con.is_handshaking() {
+				con.handshake_buffer.extend_from_slice(&read_buffer[..length]);
+				ConnectionManager::process_handshake(con, &self.config);
+			} else {
+				con.decoder.push_bytes(&read_buffer[..length]);
 			}
 
-			let payload = ws::decode_ws_packet(&mut read_buffer[..length]);
-			if payload.len() == 0 {
-				println!("Disconnection ({})", con.id);
-				con.state = ConnectionState::AwaitingDeletion;
-				continue;
-			}
+			ConnectionManager::process_messages(con, &mut self.incoming_packets);
+		}
+	}
+
+	pub fn handle_writable(&mut self, id: ConnectionID) {
+		if let Some(con) = self.connections.iter_mut().find(|c| c.id == id) {
+			con.try_write();
+		}
+	}
 
-			if let Some(packet) = Packet::parse(&payload) {
-				if !packet.is_valid_from_client() { continue }
+	fn process_handshake(con: &mut Connection, config: &Config) {
+		let header_end = con.handshake_buffer.windows(4)
+			.position(|w| w == b"\r\n\r\n")
+			.map(|pos| pos + 4);
 
-				if con.session_id.is_none() {
-					ConnectionManager::process_unauthed_packet(&mut con, &packet);
+		// Whether or not the request is complete, it can't be allowed to grow forever
+		if header_end.unwrap_or(con.handshake_buffer.len()) > config.max_handshake_size {
+			println!("Request too large ({})", con.id);
+			con.reject_handshake("HTTP/1.1 431 Request Header Fields Too Large", &[], "Request too large");
+			return;
+		}
+
+		if header_end.is_none() { return }
+
+		let request = mem::replace(&mut con.handshake_buffer, Vec::new());
+
+		let (header, request_len) = match http::Request::decode(&request) {
+			Ok(Some(decoded)) => decoded,
+
+			// The header is all there but the body isn't, which is held to the same limit
+			Ok(None) => {
+				if request.len() > config.max_handshake_size {
+					println!("Request too large ({})", con.id);
+					con.reject_handshake("HTTP/1.1 413 Payload Too Large", &[], "Request too large");
 				} else {
-					return Some((con.id, packet))
+					con.handshake_buffer = request;
 				}
+				return;
+			}
 
-			} else {
-				con.state = ConnectionState::AwaitingDeletion;
-				println!("Invalid payload ({})", con.id);
+			Err(e) => {
+				println!("Error parsing request: {}", e);
+				con.reject_handshake("HTTP/1.1 400 Bad Request", &[], e);
+				return;
 			}
+		};
+
+		// Anything after the request belongs to the websocket stream
+		let leftover = request[request_len..].to_vec();
+
+		// Everything else is for the file server, which wants the request as it arrived
+		if !header.get("Upgrade").map_or(false, |u| u.eq_ignore_ascii_case("websocket")) {
+			mem::drop(header);
+			con.handshake_buffer = request;
+			con.state = ConnectionState::PlainHttp;
+			return;
 		}
 
-		None
+		let mut response = Vec::new();
+		let result = ws::init_websocket_connection(&mut response, &header, &config.allowed_origins);
+		con.queue_bytes(&response);
+
+		match result {
+			Ok(deflate) => {
+				con.decoder = ws::FrameDecoder::new(config.max_message_size,
+					deflate.map(|d| Inflater::new(d.client_no_context_takeover)));
+				con.deflater = deflate.map(|d| Deflater::new(d.server_no_context_takeover));
+
+				con.decoder.push_bytes(&leftover);
+				con.last_ping = Instant::now();
+				con.state = ConnectionState::AwaitingHello;
+			}
+
+			Err(e) => {
+				println!("Error initialising connection: {}", e);
+				con.abort("Handshake failed");
+			}
+		}
+	}
+
+	fn process_messages(mut con: &mut Connection, incoming_packets: &mut VecDeque<(ConnectionID, Packet)>) {
+		while !con.is_handshaking() && !con.is_awaiting_deletion() {
+			let packets = match con.decoder.next_message() {
+				Ok(Some(ws::Message::Binary(payload))) => Packet::parse_message(&payload),
+
+				// Text comes from debugging tools rather than the client, and is treated as a debug message.
+				// Tools don't authenticate, so it's passed on whatever state the connection is in
+				Ok(Some(ws::Message::Text(text))) => {
+					if !con.is_closing() {
+						incoming_packets.push_back((con.id, Packet::Debug(text)));
+					}
+					continue;
+				}
+
+				Ok(Some(ws::Message::Close(reason))) => {
+					con.notify_close(reason);
+					break;
+				}
+
+				Ok(Some(ws::Message::Ping(payload))) => {
+					con.send_control_frame(0xA, &payload);
+					continue;
+				}
+
+				Ok(Some(ws::Message::Pong(payload))) => {
+					con.notify_pong(&payload);
+					continue;
+				}
+
+				Ok(None) => break,
+				Err(e) => {
+					println!("Protocol error ({}): {}, closing with {}", con.id, e, e.close_status());
+					con.close(e.close_status(), &e.to_string());
+					break;
+				}
+			};
+
+			// Anything other than control frames can be ignored while closing
+			if con.is_closing() { continue }
+
+			match packets {
+				Ok(packets) => for packet in packets {
+					// Something earlier in the batch might have closed the connection
+					if con.is_closing() { break }
+					if !packet.is_valid_from_client() { continue }
+
+					if con.session_id.is_none() {
+						ConnectionManager::process_unauthed_packet(&mut con, &packet);
+					} else {
+						incoming_packets.push_back((con.id, packet));
+					}
+				},
+
+				Err(e) => {
+					println!("Invalid packet ({}): {}", con.id, e);
+					con.close(ws::CLOSE_UNSUPPORTED_DATA, &format!("Invalid packet: {}", e));
+				}
+			}
+		}
 	}
 
 	fn process_unauthed_packet(con: &mut Connection, p: &Packet) {
+		if let ConnectionState::AwaitingHello = con.state {
+			match *p {
+				Packet::Hello(version, ref build) if version == PROTOCOL_VERSION => {
+					println!("Client {} said hello, build '{}'", con.id, build);
+					con.send_packet(&Packet::ServerHello(PROTOCOL_VERSION));
+					con.state = ConnectionState::NoAuth;
+				}
+
+				// Clients from before the version handshake start with something else.
+				// Text from debugging tools never gets here, so it doesn't have to wait for hello
+				_ => {
+					let version = match *p { Packet::Hello(version, _) => version, _ => 0 };
+					println!("Client {} speaks protocol version {}, expected {} - closing...", con.id, version, PROTOCOL_VERSION);
+					con.send_packet(&Packet::VersionMismatch(PROTOCOL_VERSION));
+					con.close(ws::CLOSE_POLICY_VIOLATION, "Protocol version mismatch");
+				}
+			}
+
+			return;
+		}
+
 		if let ConnectionState::NoAuth = con.state {
 			match *p {
 				Packet::RequestNewSession => {
@@ -240,4 +763,4 @@ impl ConnectionManager {
 		}
 
 	}
-}
\ No newline at end of file
+}
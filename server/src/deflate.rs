@@ -0,0 +1,327 @@
+use flate2::{Compress, Decompress, Compression, Flush, Status};
+use ws::FrameError;
+
+// permessage-deflate (RFC 7692)
+// https://tools.ietf.org/html/rfc7692
+
+// Compressing tiny packets just makes them bigger
+pub const MIN_COMPRESS_LEN: usize = 64;
+
+// Every compressed message ends with an empty stored block, which isn't sent
+const MESSAGE_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeflateParams {
+	// Whether the compression context is reset between messages in each direction
+	pub server_no_context_takeover: bool,
+	pub client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+	// Picks the first permessage-deflate offer in a Sec-WebSocket-Extensions header that we can accept
+	pub fn negotiate(extensions: &str) -> Option<DeflateParams> {
+		extensions.split(',')
+			.filter_map(DeflateParams::parse_offer)
+			.next()
+	}
+
+	fn parse_offer(offer: &str) -> Option<DeflateParams> {
+		let mut parts = offer.split(';').map(|s| s.trim());
+		if parts.next() != Some("permessage-deflate") {
+			return None;
+		}
+
+		let mut params = DeflateParams {
+			server_no_context_takeover: false,
+			client_no_context_takeover: false,
+		};
+
+		let mut seen = Vec::new();
+
+		for param in parts {
+			let mut kv = param.splitn(2, '=').map(|s| s.trim());
+			let key = kv.next().unwrap();
+			let value = kv.next().map(|v| v.trim_matches('"'));
+
+			// Offers with duplicate parameters are invalid
+			if seen.contains(&key) { return None }
+			seen.push(key);
+
+			match (key, value) {
+				("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+				("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+
+				// We can inflate anything regardless of the window size the client uses
+				("client_max_window_bits", None) => {},
+				("client_max_window_bits", Some(bits)) =>
+					if parse_window_bits(bits).is_none() { return None },
+
+				// miniz always compresses with a 32KB window, so we can't honour anything smaller
+				("server_max_window_bits", Some(bits)) =>
+					if parse_window_bits(bits) != Some(15) { return None },
+
+				_ => return None,
+			}
+		}
+
+		Some(params)
+	}
+
+	// The value of the Sec-WebSocket-Extensions header accepting these parameters
+	pub fn response_header(&self) -> String {
+		let mut header = "permessage-deflate".to_string();
+
+		if self.server_no_context_takeover {
+			header.push_str("; server_no_context_takeover");
+		}
+
+		if self.client_no_context_takeover {
+			header.push_str("; client_no_context_takeover");
+		}
+
+		header
+	}
+}
+
+fn parse_window_bits(bits: &str) -> Option<u8> {
+	match bits.parse::<u8>() {
+		Ok(b @ 8...15) => Some(b),
+		_ => None,
+	}
+}
+
+pub struct Deflater {
+	compress: Compress,
+	no_context_takeover: bool,
+}
+
+impl Deflater {
+	pub fn new(no_context_takeover: bool) -> Self {
+		Deflater {
+			compress: Compress::new(Compression::Default, false),
+			no_context_takeover,
+		}
+	}
+
+	pub fn compress_message(&mut self, data: &[u8]) -> Vec<u8> {
+		let mut output = Vec::with_capacity(data.len() / 2 + 64);
+		let start_in = self.compress.total_in();
+
+		loop {
+			let consumed = (self.compress.total_in() - start_in) as usize;
+			self.compress.compress_vec(&data[consumed..], &mut output, Flush::Sync);
+
+			// The flush is only finished once it stops filling the output buffer
+			let consumed = (self.compress.total_in() - start_in) as usize;
+			if consumed == data.len() && output.len() < output.capacity() {
+				break;
+			}
+
+			let len = output.len();
+			output.reserve(len);
+		}
+
+		if output.ends_with(&MESSAGE_TAIL) {
+			let len = output.len();
+			output.truncate(len - MESSAGE_TAIL.len());
+		}
+
+		if self.no_context_takeover {
+			self.compress.reset();
+		}
+
+		output
+	}
+}
+
+pub struct Inflater {
+	decompress: Decompress,
+	no_context_takeover: bool,
+}
+
+impl Inflater {
+	pub fn new(no_context_takeover: bool) -> Self {
+		Inflater {
+			decompress: Decompress::new(false),
+			no_context_takeover,
+		}
+	}
+
+	// Fails if the data is invalid or inflates to more than max_len bytes
+	pub fn decompress_message(&mut self, data: &[u8], max_len: usize) -> Result<Vec<u8>, FrameError> {
+		let mut input = Vec::with_capacity(data.len() + MESSAGE_TAIL.len());
+		input.extend_from_slice(data);
+		input.extend_from_slice(&MESSAGE_TAIL);
+
+		let mut output = Vec::with_capacity((input.len() * 4).min(max_len + 1).max(64));
+		let start_in = self.decompress.total_in();
+		let mut stream_ended = false;
+
+		loop {
+			let consumed = (self.decompress.total_in() - start_in) as usize;
+			let prev_len = output.len();
+
+			let status = match self.decompress.decompress_vec(&input[consumed..], &mut output, Flush::Sync) {
+				Ok(s) => s,
+				Err(_) => return Err(FrameError::InvalidCompressedData),
+			};
+
+			if output.len() > max_len {
+				return Err(FrameError::MessageTooBig);
+			}
+
+			let new_consumed = (self.decompress.total_in() - start_in) as usize;
+			let output_full = output.len() == output.capacity();
+
+			// The client is allowed to end the deflate stream, but then the next message starts a new one
+			if let Status::StreamEnd = status {
+				stream_ended = true;
+				break;
+			}
+
+			if new_consumed == input.len() && !output_full {
+				break;
+			}
+
+			if new_consumed == consumed && output.len() == prev_len && !output_full {
+				return Err(FrameError::InvalidCompressedData);
+			}
+
+			if output_full {
+				let len = output.len();
+				output.reserve(len.min(max_len + 1 - len).max(1));
+			}
+		}
+
+		if self.no_context_takeover || stream_ended {
+			self.decompress.reset(false);
+		}
+
+		Ok(output)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Which context takeovers were turned off, if the offer was accepted
+	fn negotiate(extensions: &str) -> Option<(bool, bool)> {
+		DeflateParams::negotiate(extensions).map(|p| (p.server_no_context_takeover, p.client_no_context_takeover))
+	}
+
+	#[test]
+	fn offers_are_negotiated() {
+		assert_eq!(negotiate("permessage-deflate"), Some((false, false)));
+		assert_eq!(negotiate("permessage-deflate; server_no_context_takeover"), Some((true, false)));
+		assert_eq!(negotiate("permessage-deflate;client_no_context_takeover ; server_no_context_takeover"), Some((true, true)));
+		assert_eq!(negotiate("x-webkit-deflate-frame, permessage-deflate; client_no_context_takeover"), Some((false, true)));
+		assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+		assert_eq!(negotiate(""), None);
+
+		assert!(DeflateParams::negotiate("permessage-deflate").unwrap().response_header() == "permessage-deflate");
+		assert!(DeflateParams::negotiate("permessage-deflate; client_no_context_takeover; server_no_context_takeover")
+			.unwrap().response_header() == "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+
+		// Takeover parameters don't have values
+		assert_eq!(negotiate("permessage-deflate; server_no_context_takeover=1"), None);
+		assert_eq!(negotiate("permessage-deflate; unknown_param"), None);
+	}
+
+	#[test]
+	fn invalid_offers_fall_back_to_the_next() {
+		let duplicate = "permessage-deflate; server_no_context_takeover; server_no_context_takeover";
+		assert_eq!(negotiate(duplicate), None);
+		assert_eq!(negotiate(&format!("{}, permessage-deflate; client_no_context_takeover", duplicate)), Some((false, true)));
+
+		// Even where the values agree
+		assert_eq!(negotiate("permessage-deflate; client_max_window_bits=10; client_max_window_bits=10"), None);
+	}
+
+	#[test]
+	fn window_bits_are_checked() {
+		// The client's window doesn't matter, so it can be left for us to pick
+		assert_eq!(negotiate("permessage-deflate; client_max_window_bits"), Some((false, false)));
+		assert_eq!(negotiate("permessage-deflate; client_max_window_bits=8"), Some((false, false)));
+		assert_eq!(negotiate("permessage-deflate; client_max_window_bits=\"12\""), Some((false, false)));
+		assert_eq!(negotiate("permessage-deflate; client_max_window_bits=7"), None);
+		assert_eq!(negotiate("permessage-deflate; client_max_window_bits=16"), None);
+
+		// Ours can't be made any smaller
+		assert_eq!(negotiate("permessage-deflate; server_max_window_bits=15"), Some((false, false)));
+		assert_eq!(negotiate("permessage-deflate; server_max_window_bits=14"), None);
+		assert_eq!(negotiate("permessage-deflate; server_max_window_bits=8"), None);
+		assert_eq!(negotiate("permessage-deflate; server_max_window_bits"), None);
+		assert_eq!(negotiate("permessage-deflate; server_max_window_bits=10, permessage-deflate"), Some((false, false)));
+	}
+
+	// From section 7.2.3 of the RFC
+	#[test]
+	fn rfc_examples_inflate() {
+		let mut inflater = Inflater::new(false);
+		assert!(inflater.decompress_message(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024) == Ok(b"Hello".to_vec()));
+
+		// The same message again, as a reference back into the first
+		assert!(inflater.decompress_message(&[0xf2, 0x00, 0x11, 0x00, 0x00], 1024) == Ok(b"Hello".to_vec()));
+
+		// With BFINAL set, after which the next message starts a new stream
+		assert!(inflater.decompress_message(&[0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00], 1024) == Ok(b"Hello".to_vec()));
+		assert!(inflater.decompress_message(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024) == Ok(b"Hello".to_vec()));
+	}
+
+	#[test]
+	fn messages_round_trip_with_context_takeover() {
+		let message = "the same text, over and over ".repeat(20);
+
+		let mut deflater = Deflater::new(false);
+		let mut inflater = Inflater::new(false);
+
+		let first = deflater.compress_message(message.as_bytes());
+		let second = deflater.compress_message(message.as_bytes());
+		assert!(first.len() < message.len());
+		assert!(!first.ends_with(&MESSAGE_TAIL));
+
+		// The second can refer back to the first, so it's smaller, and needs the first to make sense
+		assert!(second.len() < first.len());
+		assert!(Inflater::new(false).decompress_message(&second, 1<<16) != Ok(message.as_bytes().to_vec()));
+
+		assert!(inflater.decompress_message(&first, 1<<16) == Ok(message.as_bytes().to_vec()));
+		assert!(inflater.decompress_message(&second, 1<<16) == Ok(message.as_bytes().to_vec()));
+	}
+
+	#[test]
+	fn messages_round_trip_without_context_takeover() {
+		let message = "the same text, over and over ".repeat(20);
+
+		let mut deflater = Deflater::new(true);
+		let mut inflater = Inflater::new(true);
+
+		let first = deflater.compress_message(message.as_bytes());
+		let second = deflater.compress_message(message.as_bytes());
+		assert!(first == second);
+
+		assert!(inflater.decompress_message(&first, 1<<16) == Ok(message.as_bytes().to_vec()));
+		assert!(inflater.decompress_message(&second, 1<<16) == Ok(message.as_bytes().to_vec()));
+
+		// Empty messages still have to survive the trip
+		let empty = deflater.compress_message(b"");
+		assert!(inflater.decompress_message(&empty, 1<<16) == Ok(Vec::new()));
+	}
+
+	#[test]
+	fn inflated_size_is_limited() {
+		let zeros = vec![0u8; 100000];
+		let compressed = Deflater::new(false).compress_message(&zeros);
+		assert!(compressed.len() < 1000);
+
+		assert!(Inflater::new(false).decompress_message(&compressed, zeros.len()) == Ok(zeros.clone()));
+		assert!(Inflater::new(false).decompress_message(&compressed, zeros.len() - 1) == Err(FrameError::MessageTooBig));
+		assert!(Inflater::new(false).decompress_message(&compressed, 10) == Err(FrameError::MessageTooBig));
+	}
+
+	#[test]
+	fn invalid_data_is_rejected() {
+		// Block type 3 is reserved
+		assert!(Inflater::new(false).decompress_message(&[0xff, 0xff, 0xff], 1024) == Err(FrameError::InvalidCompressedData));
+	}
+}
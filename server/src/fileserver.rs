@@ -1,144 +1,844 @@
-use std::net::{TcpStream, TcpListener};
-use std::io::{Write, Read};
-use std::time;
-use std::str;
+use std::io::{self, Write, Read};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use http;
+use assets::{self, Asset, AssetCache, Encoding};
+use config::Config;
+use http::{self, Method};
+use tls::BlockingStream;
 
-pub fn start(listener: TcpListener) {
-	let mut buf = [0u8; 8<<10];
+pub struct FileServer {
+	// Canonical, so that anything resolved against them can be checked for escapes
+	roots: Vec<PathBuf>,
+	max_request_size: usize,
+	request_timeout: Duration,
+	cache: AssetCache,
+}
+
+// Counts a connection as open for as long as it's held
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+// Where a request path leads
+#[derive(Debug, PartialEq)]
+enum Resolved {
+	File(PathBuf),
+
+	// A directory asked for without a trailing slash. Its index has to be fetched from
+	// the slashed path, or relative links in it would resolve against the parent
+	Directory,
 
-	for stream in listener.incoming() {
+	NotFound,
+	Forbidden,
+}
+
+// Requests arrive from the network thread, which has already read the start of the first one.
+// Connections are kept open between requests, so each gets a thread of its own, up to a limit
+pub fn start(rx: mpsc::Receiver<(Box<dyn BlockingStream>, Vec<u8>)>, config: Config) {
+	let server = Arc::new(FileServer::new(&config));
+	let open = Arc::new(AtomicUsize::new(0));
+
+	for (mut stream, request) in rx.iter() {
 		if cfg!(debug_requests) {
 			println!("[fsrv] New connection...");
 		}
 
-		if !stream.is_ok() {
-			println!("[fsrv] Connection failed {}", stream.err().unwrap());
-			continue
+		let slot = match ConnectionSlot::take(&open, config.max_http_connections) {
+			Some(slot) => slot,
+			None => {
+				println!("Too many file server connections, turning one away");
+				let _ = write_error(&mut stream, "HTTP/1.1 503 Service Unavailable", Method::Get, false);
+				continue;
+			}
+		};
+
+		let server = server.clone();
+		thread::spawn(move || {
+			server.serve(&mut stream, request);
+			drop(slot);
+		});
+	}
+}
+
+impl ConnectionSlot {
+	fn take(open: &Arc<AtomicUsize>, limit: usize) -> Option<ConnectionSlot> {
+		if open.fetch_add(1, Ordering::SeqCst) >= limit {
+			open.fetch_sub(1, Ordering::SeqCst);
+			return None;
+		}
+
+		Some(ConnectionSlot(open.clone()))
+	}
+}
+
+impl Drop for ConnectionSlot {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+impl FileServer {
+	pub fn new(config: &Config) -> FileServer {
+		let roots = config.static_roots.iter()
+			.filter_map(|root| match fs::canonicalize(root) {
+				Ok(path) => Some(path),
+				Err(e) => {
+					println!("Not serving files from '{}': {}", root, e);
+					None
+				}
+			})
+			.collect();
+
+		FileServer {
+			roots: roots,
+			max_request_size: config.max_handshake_size,
+			request_timeout: config.http_request_timeout,
+			cache: AssetCache::new(config.asset_cache_size),
 		}
+	}
+
+	// Answers requests in the order they arrive, until the client closes the connection or asks for it
+	// to be closed, or it sits idle until a read times out. Requests can be pipelined, so there may
+	// already be more than one in buffer. A request that's still incomplete past the request timeout
+	// is dropped, so that a client can't hold a thread by sending a byte at a time
+	fn serve<S: Read + Write>(&self, stream: &mut S, mut buffer: Vec<u8>) {
+		let mut read_buffer = [0u8; 8<<10];
+		let mut started = Instant::now();
+
+		loop {
+			let handled = match http::Request::decode(&buffer) {
+				Ok(Some((request, len))) => {
+					if cfg!(debug_requests) {
+						println!("{}", String::from_utf8_lossy(&buffer[..len]));
+					}
+
+					let keep_alive = request.keep_alive();
+					if self.respond(stream, &request, keep_alive).is_err() || !keep_alive {
+						return;
+					}
+
+					Some(len)
+				}
 
-		let mut stream = stream.unwrap();
+				Ok(None) => None,
 
-		// TODO: poll or async instead of block until timeout
-		match stream.set_read_timeout(Some(time::Duration::from_millis(500))) {
-			Ok(()) => {}, Err(e) => {
-				println!("[fsrv] set_read_timeout failed: {}", e);
-				continue
+				Err(e) => {
+					println!("Parsing request: {}", e);
+					let _ = write_error(stream, "HTTP/1.1 400 Bad Request", Method::Get, false);
+					return;
+				}
+			};
+
+			if let Some(len) = handled {
+				buffer.drain(..len);
+				started = Instant::now();
+				continue;
+			}
+
+			if buffer.len() > self.max_request_size {
+				println!("Request too large");
+				let _ = write_error(stream, "HTTP/1.1 413 Payload Too Large", Method::Get, false);
+				return;
+			}
+
+			if !buffer.is_empty() && started.elapsed() > self.request_timeout {
+				println!("Request took too long to arrive");
+				let _ = write_error(stream, "HTTP/1.1 408 Request Timeout", Method::Get, false);
+				return;
+			}
+
+			match stream.read(&mut read_buffer) {
+				Ok(0) => return,
+				Ok(len) => {
+					// The time spent idle between requests doesn't count against the next one
+					if buffer.is_empty() {
+						started = Instant::now();
+					}
+
+					buffer.extend_from_slice(&read_buffer[..len]);
+				}
+
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+					if cfg!(debug_requests) {
+						println!("[fsrv] Closing idle connection");
+					}
+					return;
+				}
+
+				Err(e) => {
+					println!("Reading request: {}", e);
+					return;
+				}
 			}
 		}
+	}
 
-		let size = match stream.read(&mut buf) {
-			Ok(0) => {
-				println!("[fsrv] Zero length read");
-				continue
-			},
-			
-			Err(e) => {
-				println!("[fsrv] Error reading: {}", e);
-				continue
-			},
+	fn respond<W: Write>(&self, mut stream: &mut W, request: &http::Request, keep_alive: bool) -> io::Result<()> {
+		let method = request.method();
 
-			Ok(len) => len,
-		};
+		if method != Method::Get && method != Method::Head {
+			let mut res = http::Response::new("HTTP/1.1 405 Method Not Allowed");
+			res.set("Allow", "GET, HEAD");
+			res.set_body(&[]);
+			return write_response(&mut stream, res, method, keep_alive);
+		}
 
-		let reqstr = match str::from_utf8(&buf[0..size]) {
-			Ok(string) => string,
-			Err(_) => continue,
+		let path = match request.decoded_path() {
+			Ok(path) => path,
+			Err(_) => return write_error(&mut stream, "HTTP/1.1 400 Bad Request", method, keep_alive),
 		};
 
-		if cfg!(debug_requests) {
-			println!("{}", reqstr);
+		match self.resolve(&path) {
+			Resolved::File(filepath) => self.send_file(&mut stream, request, &filepath, keep_alive),
+
+			Resolved::Directory => {
+				let location = format!("{}/", request.path());
+				let mut res = http::Response::new("HTTP/1.1 301 Moved Permanently");
+				res.set("Location", &location);
+				res.set_body(&[]);
+				write_response(&mut stream, res, method, keep_alive)
+			}
+
+			Resolved::NotFound => write_error(&mut stream, "HTTP/1.1 404 File not found", method, keep_alive),
+			Resolved::Forbidden => write_error(&mut stream, "HTTP/1.1 403 Forbidden", method, keep_alive),
 		}
+	}
 
-		let request = match http::Request::parse(&reqstr) {
-			Ok(r) => r,
-			Err(e) => {
-				println!("Parsing request: {}", e);
-				let _ = http::Response::new("HTTP/1.1 400 Bad Request").write_to_stream(&mut stream);
-				continue;
+	// Maps a decoded request path onto a file under the first root that has it. `..` is refused
+	// outright, and the result is canonicalized so that symlinks can't lead out of the root either.
+	// Dotfiles aren't served, and directories are served by their index.html
+	fn resolve(&self, path: &str) -> Resolved {
+		if !path.starts_with('/') || path.contains('\0') {
+			return Resolved::Forbidden;
+		}
+
+		let segments = path.split('/')
+			.filter(|s| !s.is_empty() && *s != ".")
+			.collect::<Vec<_>>();
+
+		if segments.iter().any(|s| s.starts_with('.')) {
+			return Resolved::Forbidden;
+		}
+
+		for root in &self.roots {
+			let candidate = segments.iter().fold(root.clone(), |p, s| p.join(s));
+
+			let mut resolved = match fs::canonicalize(&candidate) {
+				Ok(resolved) => resolved,
+				Err(_) => continue
+			};
+
+			if !resolved.starts_with(root) {
+				return Resolved::Forbidden;
 			}
-		};
 
-		let encodings = match request.get("Accept-Encoding") {
-			Some(s) =>
-				s.split_terminator(',').map(|s| s.trim()).collect(),
+			if resolved.is_dir() {
+				if !path.ends_with('/') {
+					return Resolved::Directory;
+				}
 
-			None => Vec::new()
-		};
+				resolved = match fs::canonicalize(resolved.join("index.html")) {
+					Ok(index) => index,
+					Err(_) => continue
+				};
 
-		let encoding = encodings.iter()
-			.find(|&&enc| enc == "deflate" || enc == "gzip")
-			.map(|s| *s);
-
-		match request.uri() {
-			"/" => send_file(&mut stream, "../client/index.html", encoding),
-			"/wsclient.js" => {
-				if cfg!(hosted) {
-					send_file(&mut stream, "../client/target/asmjs-unknown-emscripten/release/wsclient.js", encoding)
-				} else {
-					send_file(&mut stream, "../client/target/asmjs-unknown-emscripten/debug/wsclient.js", encoding)
+				if !resolved.starts_with(root) {
+					return Resolved::Forbidden;
 				}
-			},
-			_ => {
-				let _ = http::Response::new("HTTP/1.1 404 File not found")
-					.write_to_stream(&mut stream);
+			}
+
+			if resolved.is_file() {
+				return Resolved::File(resolved);
 			}
 		}
+
+		Resolved::NotFound
 	}
-}
 
-fn send_file(mut stream: &mut TcpStream, filepath: &str, encoding: Option<&str>) {
-	use std::fs::File;
-	use flate2::Compression;
-	use flate2::write::{GzEncoder, DeflateEncoder};
-
-	// TODO: cache
-	let mut f = match File::open(filepath) {
-		Ok(f) => f,
-		Err(e) => {
-			println!("Couldn't open requested file '{}': {}", filepath, e);
-			let _ = http::Response::new("HTTP/1.1 500 Internal Server Error").write_to_stream(&mut stream);
-			return;
-		}
-	};
-
-	let mut body_buffer = Vec::new();
-	if let Err(e) = f.read_to_end(&mut body_buffer) {
-		println!("Couldn't read requested file '{}': {}", filepath, e);
-		let _ = http::Response::new("HTTP/1.1 500 Internal Server Error").write_to_stream(&mut stream);
-		return;
-	};
-
-	let mut res = http::Response::new("HTTP/1.1 200 OK");
-
-	if let Some(encoding) = encoding {
-		let mut encoded_buffer = Vec::new();
-
-		let write_result = match encoding {
-			"gzip" =>
-				GzEncoder::new(&mut encoded_buffer, Compression::Default)
-					.write_all(&body_buffer),
-
-			"deflate" =>
-				DeflateEncoder::new(&mut encoded_buffer, Compression::Default)
-					.write_all(&body_buffer),
-
-			_ => {
-				println!("Couldn't encode requested file '{}': Unknown encoding '{}'", filepath, encoding);
-				let _ = http::Response::new("HTTP/1.1 500 Internal Server Error").write_to_stream(&mut stream);
-				return;
+	// The file in the best encoding the client accepts, or None if it accepts none of them.
+	// A sidecar can vanish between being found and being read, so if an encoding can't be loaded
+	// the next best is tried instead
+	fn load_negotiated(&self, filepath: &Path, accept_encoding: &[&str], mut available: Vec<Encoding>) -> io::Result<Option<Arc<Asset>>> {
+		let mut failed = None;
+
+		loop {
+			let encoding = match assets::negotiate(accept_encoding, &available) {
+				Some(encoding) => encoding,
+				None => return match failed {
+					Some(e) => Err(e),
+					None => Ok(None),
+				}
+			};
+
+			match self.cache.get(filepath, encoding) {
+				Ok(asset) => return Ok(Some(asset)),
+				Err(e) => {
+					available.retain(|&a| a != encoding);
+					failed = Some(e);
+				}
+			}
+		}
+	}
+
+	fn send_file<W: Write>(&self, mut stream: &mut W, request: &http::Request, filepath: &Path, keep_alive: bool) -> io::Result<()> {
+		let method = request.method();
+
+		let available = match assets::available_encodings(filepath) {
+			Ok(available) => available,
+			Err(e) => {
+				println!("Couldn't load requested file '{}': {}", filepath.display(), e);
+				return write_error(&mut stream, "HTTP/1.1 500 Internal Server Error", method, keep_alive);
 			}
 		};
 
-		if write_result.is_ok() {
-			body_buffer = encoded_buffer;
-			res.set("Content-Encoding", encoding);
+		let asset = match self.load_negotiated(filepath, &request.get_all("Accept-Encoding"), available) {
+			Ok(Some(asset)) => asset,
+
+			Ok(None) => {
+				let mut res = http::Response::new("HTTP/1.1 406 Not Acceptable");
+				res.set("Vary", "Accept-Encoding");
+				res.set_body(&[]);
+				return write_response(&mut stream, res, method, keep_alive);
+			}
+
+			Err(e) => {
+				println!("Couldn't load requested file '{}': {}", filepath.display(), e);
+				return write_error(&mut stream, "HTTP/1.1 500 Internal Server Error", method, keep_alive);
+			}
+		};
+
+		let last_modified = http::format_date(asset.last_modified());
+
+		// Clients have to check back every time, so that a rebuilt client is picked up on reload.
+		// If nothing's changed the answer is just a 304
+		let mut res = if is_not_modified(request, &asset) {
+			http::Response::new("HTTP/1.1 304 Not Modified")
 		} else {
-			println!("Couldn't encode requested file '{}': {}", filepath, write_result.err().unwrap());
+			let mut res = http::Response::new("HTTP/1.1 200 OK");
+			res.set("Content-Type", content_type(filepath));
+			if asset.encoding != Encoding::Identity {
+				res.set("Content-Encoding", asset.encoding.name());
+			}
+			res.set_body(&asset.body);
+			res
+		};
+
+		res.set("Vary", "Accept-Encoding");
+		res.set("ETag", &asset.etag);
+		res.set("Last-Modified", &last_modified);
+		res.set("Cache-Control", "no-cache");
+		write_response(&mut stream, res, method, keep_alive)
+	}
+}
+
+// If-None-Match takes precedence over If-Modified-Since, which is ignored when both are sent
+fn is_not_modified(request: &http::Request, asset: &Asset) -> bool {
+	let tags = request.get_all("If-None-Match");
+
+	if !tags.is_empty() {
+		return tags.iter()
+			.flat_map(|t| t.split(','))
+			.map(str::trim)
+			.any(|t| t == "*" || t.trim_start_matches("W/") == asset.etag);
+	}
+
+	match request.get("If-Modified-Since").and_then(http::parse_date) {
+		Some(since) => asset.last_modified() <= since,
+		None => false
+	}
+}
+
+// Anything not listed is sent as plain bytes
+fn content_type(path: &Path) -> &'static str {
+	let extension = path.extension()
+		.and_then(|e| e.to_str())
+		.map(|e| e.to_ascii_lowercase())
+		.unwrap_or(String::new());
+
+	match extension.as_str() {
+		"html" | "htm" => "text/html; charset=utf-8",
+		"css" => "text/css; charset=utf-8",
+		"js" => "application/javascript",
+		"json" | "map" => "application/json",
+		"wasm" => "application/wasm",
+		"txt" | "vert" | "frag" | "glsl" => "text/plain; charset=utf-8",
+		"png" => "image/png",
+		"jpg" | "jpeg" => "image/jpeg",
+		"gif" => "image/gif",
+		"svg" => "image/svg+xml",
+		"ico" => "image/x-icon",
+		"woff" => "font/woff",
+		"woff2" => "font/woff2",
+		"ttf" => "font/ttf",
+		_ => "application/octet-stream",
+	}
+}
+
+// Every response has a length and says whether the connection stays open, so the client can tell
+// where it ends without waiting for the socket to close. HEAD gets everything but the body
+fn write_response<W: Write>(mut stream: &mut W, mut res: http::Response, method: Method, keep_alive: bool) -> io::Result<()> {
+	res.set("Connection", if keep_alive { "keep-alive" } else { "close" });
+
+	if method == Method::Head {
+		res.write_head_to_stream(&mut stream)
+	} else {
+		res.write_to_stream(&mut stream)
+	}
+}
+
+fn write_error<W: Write>(mut stream: &mut W, status_line: &str, method: Method, keep_alive: bool) -> io::Result<()> {
+	let mut res = http::Response::new(status_line);
+	res.set_body(&[]);
+	write_response(&mut stream, res, method, keep_alive)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	#[cfg(unix)]
+	use std::os::unix::fs::symlink;
+	use std::process;
+	use std::time::{SystemTime, Duration};
+
+	// A client whose requests have all been sent, and who reads everything written back
+	struct TestStream {
+		input: io::Cursor<Vec<u8>>,
+		output: Vec<u8>,
+	}
+
+	impl Read for TestStream {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.input.read(buf) }
+	}
+
+	impl Write for TestStream {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.output.write(buf) }
+		fn flush(&mut self) -> io::Result<()> { Ok(()) }
+	}
+
+	fn test_server() -> FileServer {
+		let root = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/static");
+		let build = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/static_build");
+
+		let mut config = Config::default();
+		config.static_roots = vec![root.to_string(), build.to_string(), "/does/not/exist".to_string()];
+		FileServer::new(&config)
+	}
+
+	fn test_file(path: &str) -> Vec<u8> {
+		fs::read(format!("{}/test_data/{}", env!("CARGO_MANIFEST_DIR"), path)).unwrap()
+	}
+
+	fn serve_all(first: &str, rest: &str) -> Vec<(String, Vec<u8>)> {
+		serve_with(&test_server(), first, rest)
+	}
+
+	// The network thread hands over the first request, and the rest are read from the stream
+	fn serve_with(server: &FileServer, first: &str, rest: &str) -> Vec<(String, Vec<u8>)> {
+		let mut stream = TestStream {
+			input: io::Cursor::new(rest.as_bytes().to_vec()),
+			output: Vec::new(),
+		};
+
+		server.serve(&mut stream, first.as_bytes().to_vec());
+
+		// Splits the output back into responses, going by their lengths
+		let mut output = &stream.output[..];
+		let mut responses = Vec::new();
+
+		while !output.is_empty() {
+			let header_end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+			let header = String::from_utf8(output[..header_end].to_vec()).unwrap();
+
+			// 304s have no body, and so no length either
+			let body_len = header.lines()
+				.find(|l| l.starts_with("Content-Length: "))
+				.map(|l| l["Content-Length: ".len()..].parse::<usize>().unwrap())
+				.or(if header.starts_with("HTTP/1.1 304 ") { Some(0) } else { None })
+				.expect("Response without a Content-Length");
+
+			responses.push((header, output[header_end..header_end + body_len].to_vec()));
+			output = &output[header_end + body_len..];
 		}
+
+		responses
+	}
+
+	#[test]
+	fn requests_are_answered_until_the_connection_closes() {
+		let index = test_file("static/index.html");
+
+		// Two pipelined requests arrive together, and another arrives later
+		let responses = serve_all("GET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\n", "GET / HTTP/1.1\r\n\r\n");
+		assert_eq!(responses.len(), 3);
+
+		assert!(responses[0].0.starts_with("HTTP/1.1 200 "));
+		assert!(responses[0].0.contains("Connection: keep-alive\r\n"));
+		assert!(responses[0].1 == index);
+
+		assert!(responses[1].0.starts_with("HTTP/1.1 404 "));
+		assert!(responses[1].1.is_empty());
+
+		assert!(responses[2].0.starts_with("HTTP/1.1 200 "));
 	}
 
-	res.set_body(&body_buffer);
-	let _ = res.write_to_stream(&mut stream);
-}
\ No newline at end of file
+	#[test]
+	fn connection_close_is_respected() {
+		let responses = serve_all("GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n", "");
+		assert_eq!(responses.len(), 1);
+		assert!(responses[0].0.contains("Connection: close\r\n"));
+
+		// HTTP/1.0 closes by default
+		let responses = serve_all("GET / HTTP/1.0\r\n\r\n", "GET / HTTP/1.0\r\n\r\n");
+		assert_eq!(responses.len(), 1);
+
+		let responses = serve_all("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", "GET / HTTP/1.0\r\n\r\n");
+		assert_eq!(responses.len(), 2);
+		assert!(responses[0].0.contains("Connection: keep-alive\r\n"));
+	}
+
+	#[test]
+	fn head_and_unsupported_methods() {
+		let index = test_file("static/index.html");
+
+		let mut stream = TestStream { input: io::Cursor::new(Vec::new()), output: Vec::new() };
+		test_server().serve(&mut stream, b"HEAD / HTTP/1.1\r\n\r\n".to_vec());
+
+		let response = String::from_utf8(stream.output).unwrap();
+		assert!(response.starts_with("HTTP/1.1 200 "));
+		assert!(response.contains(&format!("Content-Length: {}\r\n", index.len())));
+		assert!(response.ends_with("\r\n\r\n"));
+
+		let responses = serve_all("POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET / HTTP/1.1\r\n\r\n", "");
+		assert_eq!(responses.len(), 2);
+		assert!(responses[0].0.starts_with("HTTP/1.1 405 "));
+		assert!(responses[0].0.contains("Allow: GET, HEAD\r\n"));
+		assert!(responses[1].0.starts_with("HTTP/1.1 200 "));
+	}
+
+	#[test]
+	fn bad_requests_close_the_connection() {
+		let responses = serve_all("GET / HTTP/1.1\r\n\r\nGET / HTTP/3\r\n\r\n", "GET / HTTP/1.1\r\n\r\n");
+		assert_eq!(responses.len(), 2);
+		assert!(responses[1].0.starts_with("HTTP/1.1 400 "));
+		assert!(responses[1].0.contains("Connection: close\r\n"));
+	}
+
+	// Sends its input a byte at a time, with a pause before each
+	struct SlowStream {
+		input: io::Cursor<Vec<u8>>,
+		output: Vec<u8>,
+		delay: Duration,
+	}
+
+	impl Read for SlowStream {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			::std::thread::sleep(self.delay);
+			let len = buf.len().min(1);
+			self.input.read(&mut buf[..len])
+		}
+	}
+
+	impl Write for SlowStream {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.output.write(buf) }
+		fn flush(&mut self) -> io::Result<()> { Ok(()) }
+	}
+
+	#[test]
+	fn slow_requests_time_out() {
+		let mut server = test_server();
+		server.request_timeout = Duration::from_millis(50);
+
+		// Each read comes well within the idle timeout, but the request as a whole doesn't
+		let mut stream = SlowStream {
+			input: io::Cursor::new(b"T / HTTP/1.1\r\n\r\n".to_vec()),
+			output: Vec::new(),
+			delay: Duration::from_millis(10),
+		};
+
+		server.serve(&mut stream, b"GE".to_vec());
+		let response = String::from_utf8(stream.output).unwrap();
+		assert!(response.starts_with("HTTP/1.1 408 "));
+		assert!(response.contains("Connection: close\r\n"));
+
+		// A slow request that still arrives in time is answered, and so is the next one
+		let mut stream = SlowStream {
+			input: io::Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+			output: Vec::new(),
+			delay: Duration::from_millis(2),
+		};
+
+		server.request_timeout = Duration::from_millis(500);
+		server.serve(&mut stream, b"GET / HTTP/1.1\r\n\r\n".to_vec());
+		let response = String::from_utf8(stream.output).unwrap();
+		assert_eq!(response.matches("HTTP/1.1 200 ").count(), 2);
+	}
+
+	#[test]
+	fn connections_are_limited() {
+		let open = Arc::new(AtomicUsize::new(0));
+		let first = ConnectionSlot::take(&open, 2).unwrap();
+		let second = ConnectionSlot::take(&open, 2).unwrap();
+		assert!(ConnectionSlot::take(&open, 2).is_none());
+
+		drop(first);
+		assert!(ConnectionSlot::take(&open, 2).is_some());
+		assert_eq!(open.load(Ordering::SeqCst), 1);
+		drop(second);
+		assert_eq!(open.load(Ordering::SeqCst), 0);
+	}
+
+	#[test]
+	fn files_are_served_from_the_first_root_that_has_them() {
+		let responses = serve_all("GET /style.css HTTP/1.1\r\n\r\n\
+			GET /app.js HTTP/1.1\r\n\r\n\
+			GET /index.html HTTP/1.1\r\n\r\n\
+			GET /sub/Image.PNG HTTP/1.1\r\n\r\n", "");
+
+		assert_eq!(responses.len(), 4);
+		assert!(responses[0].0.contains("Content-Type: text/css; charset=utf-8\r\n"));
+		assert!(responses[0].1 == test_file("static/style.css"));
+		assert!(responses[1].0.contains("Content-Type: application/javascript\r\n"));
+		assert!(responses[1].1 == test_file("static_build/app.js"));
+		assert!(responses[2].0.contains("Content-Type: text/html; charset=utf-8\r\n"));
+		assert!(responses[2].1 == test_file("static/index.html"));
+		assert!(responses[3].0.contains("Content-Type: image/png\r\n"));
+	}
+
+	#[test]
+	fn directories_are_served_by_their_index() {
+		let responses = serve_all("GET /sub/ HTTP/1.1\r\n\r\nGET /sub?x=1 HTTP/1.1\r\n\r\n", "");
+		assert_eq!(responses.len(), 2);
+
+		assert!(responses[0].0.starts_with("HTTP/1.1 200 "));
+		assert!(responses[0].1 == test_file("static/sub/index.html"));
+
+		assert!(responses[1].0.starts_with("HTTP/1.1 301 "));
+		assert!(responses[1].0.contains("Location: /sub/\r\n"));
+	}
+
+	#[test]
+	fn paths_cant_escape_the_root() {
+		let server = test_server();
+
+		for path in &["/../selfsigned.key", "/sub/../../selfsigned.key", "/.hidden", "/a\0b"] {
+			assert_eq!(server.resolve(path), Resolved::Forbidden, "{}", path);
+		}
+
+		assert_eq!(server.resolve("/missing.js"), Resolved::NotFound);
+
+		// Encoded dots are decoded before the path is checked
+		let responses = serve_all("GET /%2e%2e/selfsigned.key HTTP/1.1\r\n\r\nGET /sub/%2E%2E/%2E%2E/selfsigned.key HTTP/1.1\r\n\r\n", "");
+		assert_eq!(responses.len(), 2);
+		assert!(responses[0].0.starts_with("HTTP/1.1 403 "));
+		assert!(responses[1].0.starts_with("HTTP/1.1 403 "));
+	}
+
+	// The fixtures' symlinks only check out as symlinks on unix
+	#[cfg(unix)]
+	#[test]
+	fn symlinks_have_to_stay_inside_the_root() {
+		let server = test_server();
+		assert_eq!(server.resolve("/escape"), Resolved::Forbidden);
+
+		let responses = serve_all("GET /inside/ HTTP/1.1\r\n\r\n", "");
+		assert!(responses[0].0.starts_with("HTTP/1.1 200 "));
+		assert!(responses[0].1 == test_file("static/sub/index.html"));
+	}
+
+	fn header<'a>(response: &'a str, key: &str) -> &'a str {
+		let prefix = format!("{}: ", key);
+		response.lines()
+			.find(|l| l.starts_with(&prefix))
+			.map(|l| &l[prefix.len()..])
+			.unwrap()
+	}
+
+	#[test]
+	fn unchanged_files_are_not_sent_again() {
+		let first = serve_all("GET /style.css HTTP/1.1\r\n\r\n", "");
+		let etag = header(&first[0].0, "ETag").to_string();
+		let last_modified = header(&first[0].0, "Last-Modified").to_string();
+		assert!(header(&first[0].0, "Cache-Control") == "no-cache");
+
+		let requests = [
+			format!("If-None-Match: {}", etag),
+			format!("If-None-Match: \"other\", W/{}", etag),
+			"If-None-Match: *".to_string(),
+			format!("If-Modified-Since: {}", last_modified),
+			format!("If-Modified-Since: {}", http::format_date(::std::time::SystemTime::now())),
+		];
+
+		for condition in &requests {
+			let responses = serve_all(&format!("GET /style.css HTTP/1.1\r\n{}\r\n\r\n", condition), "");
+			assert!(responses[0].0.starts_with("HTTP/1.1 304 "), "{}", condition);
+			assert!(header(&responses[0].0, "ETag") == etag);
+			assert!(responses[0].1.is_empty());
+		}
+
+		let requests = [
+			"If-None-Match: \"other\"".to_string(),
+			"If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT".to_string(),
+			"If-Modified-Since: yesterday".to_string(),
+
+			// A tag that doesn't match wins over a date that does
+			format!("If-None-Match: \"other\"\r\nIf-Modified-Since: {}", last_modified),
+		];
+
+		for condition in &requests {
+			let responses = serve_all(&format!("GET /style.css HTTP/1.1\r\n{}\r\n\r\n", condition), "");
+			assert!(responses[0].0.starts_with("HTTP/1.1 200 "), "{}", condition);
+			assert!(responses[0].1 == first[0].1);
+		}
+	}
+
+	// A static root of its own, for tests that need to control the files' ages
+	fn temp_root(name: &str) -> (FileServer, PathBuf) {
+		let root = env::temp_dir().join(format!("wsserver-{}-{}", name, process::id()));
+		let _ = fs::remove_dir_all(&root);
+		fs::create_dir_all(&root).unwrap();
+
+		let mut config = Config::default();
+		config.static_roots = vec![root.to_str().unwrap().to_string()];
+		(FileServer::new(&config), root)
+	}
+
+	fn set_age(path: &Path, time: SystemTime) {
+		fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+	}
+
+	fn get(server: &FileServer, path: &str, accept_encoding: &str) -> (String, Vec<u8>) {
+		let request = format!("GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", path, accept_encoding);
+		serve_with(server, &request, "").remove(0)
+	}
+
+	fn content_encoding(response: &str) -> Option<&str> {
+		response.lines()
+			.find(|l| l.starts_with("Content-Encoding: "))
+			.map(|l| &l["Content-Encoding: ".len()..])
+	}
+
+	#[test]
+	fn encodings_follow_the_clients_weights() {
+		let (server, root) = temp_root("weights");
+		let big = "let x = 1;\n".repeat(200);
+		fs::write(root.join("big.js"), &big).unwrap();
+		fs::write(root.join("small.js"), "let x = 1;\n").unwrap();
+
+		let cases = [
+			("gzip", Some("gzip")),
+			("deflate, gzip;q=0.5", Some("deflate")),
+			("deflate, gzip", Some("gzip")),
+			("gzip;q=0, deflate;q=0", None),
+			("identity;q=0, deflate;q=0.1", Some("deflate")),
+			("br", None),
+		];
+
+		for &(accept, expected) in &cases {
+			let (response, _) = get(&server, "/big.js", accept);
+			assert!(response.starts_with("HTTP/1.1 200 "), "{}", accept);
+			assert!(content_encoding(&response) == expected, "{}", accept);
+			assert!(header(&response, "Vary") == "Accept-Encoding");
+		}
+
+		// No header means no encoding
+		let (response, body) = serve_with(&server, "GET /big.js HTTP/1.1\r\n\r\n", "").remove(0);
+		assert!(content_encoding(&response) == None);
+		assert!(body == big.as_bytes());
+
+		// Nothing left to send it in
+		for accept in &["*;q=0", "gzip;q=0, identity;q=0"] {
+			let (response, body) = get(&server, "/big.js", accept);
+			assert!(response.starts_with("HTTP/1.1 406 "), "{}", accept);
+			assert!(body.is_empty());
+		}
+
+		// Small files aren't compressed, but still vary in principle
+		let (response, body) = get(&server, "/small.js", "gzip");
+		assert!(content_encoding(&response) == None);
+		assert!(header(&response, "Vary") == "Accept-Encoding");
+		assert!(body == b"let x = 1;\n");
+
+		// Each encoding is tagged separately, so one can't stand in for another
+		let (identity, _) = get(&server, "/big.js", "identity");
+		let request = format!("GET /big.js HTTP/1.1\r\nAccept-Encoding: gzip\r\nIf-None-Match: {}\r\n\r\n", header(&identity, "ETag"));
+		let (gzipped, _) = serve_with(&server, &request, "").remove(0);
+		assert!(gzipped.starts_with("HTTP/1.1 200 "));
+		assert!(header(&gzipped, "ETag") != header(&identity, "ETag"));
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn precompressed_sidecars_are_preferred() {
+		let (server, root) = temp_root("sidecars");
+		let big = "let x = 1;\n".repeat(200);
+		let now = SystemTime::now();
+
+		fs::write(root.join("big.js"), &big).unwrap();
+		fs::write(root.join("big.js.gz"), "precompressed gzip").unwrap();
+		fs::write(root.join("big.js.br"), "precompressed brotli").unwrap();
+		set_age(&root.join("big.js"), now - Duration::from_secs(10));
+
+		let (response, body) = get(&server, "/big.js", "gzip, br");
+		assert!(content_encoding(&response) == Some("br"));
+		assert!(header(&response, "Content-Type") == "application/javascript");
+		assert!(body == b"precompressed brotli");
+
+		let (response, body) = get(&server, "/big.js", "br;q=0.5, gzip");
+		assert!(content_encoding(&response) == Some("gzip"));
+		assert!(body == b"precompressed gzip");
+
+		// Once the file is newer than its sidecars they're stale, and ignored
+		set_age(&root.join("big.js"), now + Duration::from_secs(10));
+
+		let (response, body) = get(&server, "/big.js", "gzip");
+		assert!(content_encoding(&response) == Some("gzip"));
+		assert!(body != b"precompressed gzip");
+
+		let (response, body) = get(&server, "/big.js", "br");
+		assert!(content_encoding(&response) == None);
+		assert!(body == big.as_bytes());
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	// Sidecars can't be symlinks, which could lead anywhere
+	#[cfg(unix)]
+	#[test]
+	fn symlinked_sidecars_are_ignored() {
+		let (server, root) = temp_root("symlinked");
+		let big = "let x = 1;\n".repeat(200);
+
+		fs::write(root.join("big.js"), &big).unwrap();
+		fs::write(root.join("elsewhere.br"), "precompressed brotli").unwrap();
+		symlink(root.join("elsewhere.br"), root.join("big.js.br")).unwrap();
+		set_age(&root.join("big.js"), SystemTime::now() - Duration::from_secs(10));
+
+		let (response, body) = get(&server, "/big.js", "br");
+		assert!(content_encoding(&response) == None);
+		assert!(body == big.as_bytes());
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn vanished_sidecars_fall_back_to_the_next_encoding() {
+		let (server, root) = temp_root("vanished");
+		let path = root.join("big.js");
+		fs::write(&path, "let x = 1;\n".repeat(200)).unwrap();
+
+		// As if big.js.br had been there when the encodings were listed, but was gone by the time it was read
+		let available = vec![Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+		let asset = server.load_negotiated(&path, &["br, gzip"], available.clone()).unwrap().unwrap();
+		assert!(asset.encoding == Encoding::Gzip);
+
+		assert!(server.load_negotiated(&path, &["br, identity;q=0"], available.clone()).is_err());
+		assert!(server.load_negotiated(&path, &["compress, identity;q=0"], available).unwrap().is_none());
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+}
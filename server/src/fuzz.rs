@@ -0,0 +1,398 @@
+// Mutation fuzzing for everything that parses bytes straight off the network.
+// Each target is run over its seed corpus in test_data/fuzz/corpus, and then over random mutations
+// of it. An input that panics is saved to the temp directory, or with WIRE_FUZZ_SAVE=1 straight to
+// test_data/fuzz/regressions, and fuzz_regressions replays everything there on every test run.
+//
+// The seeds are made up rather than captured, by seed_corpus below, which builds them with the real
+// encoders. Seeds left behind by an older wire format are still fine to mutate, so the corpus is
+// only regenerated on its own, never as part of a change to the format:
+//   cargo test generate_seed_corpus -- --ignored
+//
+// A normal test run only does a quick pass. For a longer one, with a new seed each time:
+//   WIRE_FUZZ_ITERS=1000000 WIRE_FUZZ_SEED=$RANDOM cargo test --release fuzz_ -- --nocapture
+
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+use std::str;
+
+use common::{Packet, Batch, PROTOCOL_VERSION};
+use deflate::{Deflater, Inflater};
+use http;
+use sha1;
+use ws;
+
+const DEFAULT_ITERS: usize = 5000;
+const DEFAULT_SEED: u64 = 0x5EED_0F_F1BE;
+const MAX_INPUT_LEN: usize = 4096;
+
+const TARGETS: &[(&str, fn(&[u8]))] = &[
+	("packet", packet_target),
+	("frame", frame_target),
+	("http", http_target),
+];
+
+// Anything that parses has to encode back to the same bytes, and batches have to survive being rebuilt
+fn packet_target(data: &[u8]) {
+	if let Ok(packet) = Packet::parse(data) {
+		let mut encoded = Vec::new();
+		packet.write(&mut encoded);
+		assert!(encoded == data, "Packet didn't survive a round trip");
+	}
+
+	if let Ok(packets) = Packet::parse_message(data) {
+		let mut batch = Batch::new();
+		for packet in &packets {
+			batch.push(packet);
+		}
+
+		let mut encoded = Vec::new();
+		batch.write(&mut encoded);
+
+		let reparsed = Packet::parse_message(&encoded).expect("Rebuilt batch doesn't parse");
+		assert!(reparsed.len() == packets.len(), "Batch didn't survive a round trip");
+	}
+}
+
+// The same bytes as a single frame, and as what a connection read off its socket,
+// with and without permessage-deflate
+fn frame_target(data: &[u8]) {
+	if let Ok(Some((frame, len))) = ws::decode_ws_packet(data) {
+		assert!(len <= data.len(), "Frame is longer than its input");
+		assert!(frame.payload.len() <= len, "Payload is longer than its frame");
+	}
+
+	for &compressed in &[false, true] {
+		let inflater = if compressed { Some(Inflater::new(false)) } else { None };
+		let mut decoder = ws::FrameDecoder::new(1<<16, inflater);
+		decoder.push_bytes(data);
+
+		while let Ok(Some(message)) = decoder.next_message() {
+			if let ws::Message::Binary(payload) = message {
+				packet_target(&payload);
+			}
+		}
+	}
+}
+
+// Requests go on to the websocket handshake, since that's what picks the headers apart
+fn http_target(data: &[u8]) {
+	if let Ok(Some((request, len))) = http::Request::decode(data) {
+		assert!(len <= data.len(), "Request is longer than its input");
+		let _ = ws::init_websocket_connection(&mut Vec::new(), &request, &[]);
+	}
+}
+
+fn data_dir(kind: &str, target: &str) -> PathBuf {
+	[env!("CARGO_MANIFEST_DIR"), "test_data", "fuzz", kind, target].iter().collect()
+}
+
+fn load_dir(dir: &PathBuf) -> Vec<(PathBuf, Vec<u8>)> {
+	let mut entries = match fs::read_dir(dir) {
+		Ok(entries) => entries
+			.map(|e| e.unwrap().path())
+			.map(|path| { let data = fs::read(&path).unwrap(); (path, data) })
+			.collect::<Vec<_>>(),
+
+		Err(_) => Vec::new()
+	};
+
+	entries.sort();
+	entries
+}
+
+// Inputs are named by their hash, so the same input is only ever stored once
+fn file_name(input: &[u8]) -> String {
+	let mut m = sha1::Sha1::new();
+	m.update(input);
+	m.digest().bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A plain test run leaves the source tree alone
+fn save_regression(target: &str, input: &[u8]) -> PathBuf {
+	let dir = if env_or("WIRE_FUZZ_SAVE", 0) == 1 {
+		data_dir("regressions", target)
+	} else {
+		env::temp_dir().join("wire-fuzz").join(target)
+	};
+
+	fs::create_dir_all(&dir).unwrap();
+
+	let path = dir.join(file_name(input));
+	fs::write(&path, input).unwrap();
+	path
+}
+
+fn env_or<T: str::FromStr>(key: &str, default: T) -> T {
+	env::var(key).ok()
+		.and_then(|v| v.parse().ok())
+		.unwrap_or(default)
+}
+
+// xorshift64*, so a seed always produces the same run
+struct Rng(u64);
+
+impl Rng {
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 >> 12;
+		self.0 ^= self.0 << 25;
+		self.0 ^= self.0 >> 27;
+		self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+	}
+
+	fn below(&mut self, n: usize) -> usize {
+		(self.next() % n as u64) as usize
+	}
+
+	fn byte(&mut self) -> u8 {
+		self.next() as u8
+	}
+}
+
+// Bytes that sit on the boundaries the parsers care about: length encodings, opcodes and http syntax
+const INTERESTING_BYTES: &[u8] = &[0x00, 0x01, 0x7E, 0x7F, 0x80, 0x81, 0x82, 0x88, 0x89, 0xC1, 0xFF, b'\r', b'\n', b':', b' '];
+const INTERESTING_LENGTHS: &[u64] = &[0, 1, 125, 126, 127, 65535, 65536, 1<<31, 1<<63, ::std::u64::MAX];
+
+// Multibyte characters of every length, since flipping single bytes almost never makes valid utf8,
+// and anything that slices a str by byte offset only breaks on one
+const INTERESTING_CHARS: &[&str] = &["\u{e9}", "\u{7ff}", "\u{2713}", "\u{feff}", "\u{1f600}", "\u{10ffff}"];
+
+fn mutate(rng: &mut Rng, input: &mut Vec<u8>, corpus: &[(PathBuf, Vec<u8>)]) {
+	for _ in 0..1 + rng.below(4) {
+		let len = input.len();
+
+		match rng.below(9) {
+			0 if len > 0 => { let i = rng.below(len); input[i] ^= 1 << rng.below(8); }
+			1 if len > 0 => { let i = rng.below(len); input[i] = rng.byte(); }
+			2 if len > 0 => { let i = rng.below(len); input[i] = INTERESTING_BYTES[rng.below(INTERESTING_BYTES.len())]; }
+			3 => { let i = rng.below(len + 1); input.insert(i, rng.byte()); }
+
+			4 if len > 0 => {
+				let start = rng.below(len);
+				let end = start + 1 + rng.below(len - start);
+				input.drain(start..end);
+			}
+
+			5 if len > 0 => {
+				let start = rng.below(len);
+				let end = start + 1 + rng.below((len - start).min(64));
+				let copy = input[start..end].to_vec();
+				let at = rng.below(len + 1);
+				input.splice(at..at, copy);
+			}
+
+			// Overwrite with a big endian length, as a frame header would carry it
+			6 if len > 0 => {
+				let value = INTERESTING_LENGTHS[rng.below(INTERESTING_LENGTHS.len())];
+				let width = [1, 2, 4, 8][rng.below(4)];
+				let at = rng.below(len);
+				for i in 0..width.min(len - at) {
+					input[at + i] = (value >> ((width - 1 - i) * 8)) as u8;
+				}
+			}
+
+			// Splice in part of another input
+			7 => {
+				let other = &corpus[rng.below(corpus.len())].1;
+				if !other.is_empty() {
+					let start = rng.below(other.len());
+					let at = rng.below(len + 1);
+					input.truncate(at);
+					input.extend_from_slice(&other[start..]);
+				}
+			}
+
+			8 => {
+				let c = INTERESTING_CHARS[rng.below(INTERESTING_CHARS.len())].as_bytes();
+				let at = rng.below(len + 1);
+				input.splice(at..at, c.iter().cloned());
+			}
+
+			_ => input.truncate(rng.below(len + 1)),
+		}
+	}
+
+	input.truncate(MAX_INPUT_LEN);
+}
+
+fn fuzz(name: &str) {
+	let target = TARGETS.iter().find(|t| t.0 == name).unwrap().1;
+
+	let corpus = load_dir(&data_dir("corpus", name));
+	assert!(!corpus.is_empty(), "No seed corpus for {}", name);
+
+	for &(ref path, ref input) in &corpus {
+		if panic::catch_unwind(|| target(input)).is_err() {
+			panic!("{} panicked on seed {}", name, path.display());
+		}
+	}
+
+	let iters = env_or("WIRE_FUZZ_ITERS", DEFAULT_ITERS);
+	let seed = env_or("WIRE_FUZZ_SEED", DEFAULT_SEED);
+	println!("Fuzzing {} for {} iterations with seed {}", name, iters, seed);
+
+	let mut rng = Rng(seed | 1);
+	for _ in 0..iters {
+		let mut input = corpus[rng.below(corpus.len())].1.clone();
+		mutate(&mut rng, &mut input, &corpus);
+
+		if panic::catch_unwind(|| target(&input)).is_err() {
+			let path = save_regression(name, &input);
+			panic!("{} panicked on a {} byte input, saved to {}", name, input.len(), path.display());
+		}
+	}
+}
+
+// Something of everything each parser accepts, plus a few things it has to refuse
+fn seed_corpus(target: &str) -> Vec<Vec<u8>> {
+	let packet = |p: Packet| { let mut buf = Vec::new(); p.write(&mut buf); buf };
+
+	let packets = vec![
+		packet(Packet::Debug(String::from("hello from the client"))),
+		packet(Packet::Debug("x".repeat(300))),
+		packet(Packet::RequestNewSession),
+		packet(Packet::AttemptAuthSession(0x2664)),
+		packet(Packet::RequestDownloadWorld),
+		packet(Packet::Hello(PROTOCOL_VERSION, String::from("0.1.0"))),
+		packet(Packet::AuthSuccessful(0x2664)),
+		packet(Packet::AuthFail),
+		packet(Packet::NewSession(0x2EED)),
+		packet(Packet::ServerHello(PROTOCOL_VERSION)),
+		packet(Packet::VersionMismatch(PROTOCOL_VERSION)),
+	];
+
+	let mut batch = Batch::new();
+	batch.push(&Packet::ServerHello(PROTOCOL_VERSION));
+	batch.push(&Packet::Debug(String::from("hi")));
+	let mut batched = Vec::new();
+	batch.write(&mut batched);
+
+	match target {
+		"packet" => {
+			let mut seeds = packets;
+			seeds.push(batched);
+			seeds.push(b"text \xE2\x9C\x93 message".to_vec());
+			seeds
+		}
+
+		"frame" => {
+			let frame = |opcode: u8, payload: &[u8]| { let mut buf = Vec::new(); ws::encode_ws_frame(&mut buf, opcode, payload); masked(buf) };
+
+			let mut seeds = packets.iter().map(|p| frame(0x2, p)).collect::<Vec<_>>();
+			seeds.push(frame(0x2, &batched));
+			seeds.push(frame(0x1, "text \u{2713} message".as_bytes()));
+			seeds.push(frame(0x9, b"keepalive"));
+			seeds.push(frame(0x8, &[0x03, 0xE8, b'b', b'y', b'e']));
+
+			// A message in two fragments, with a ping between them
+			let mut fragmented = frame(0x2, &packets[0][..8]);
+			fragmented[0] &= 0x7F;
+			fragmented.extend(frame(0x9, b""));
+			let mut last = frame(0x0, &packets[0][8..]);
+			last[0] |= 0x80;
+			fragmented.extend(last);
+			seeds.push(fragmented);
+
+			// Compressed with permessage-deflate, once with the context taken over from the first
+			let mut deflater = Deflater::new(false);
+			let mut compressed = Vec::new();
+			for _ in 0..2 {
+				let mut buf = Vec::new();
+				ws::encode_compressed_ws_packet(&mut buf, &deflater.compress_message(&packets[1]));
+				compressed.extend(masked(buf));
+			}
+			seeds.push(compressed);
+
+			// Servers don't mask, which a client isn't allowed to get away with
+			let mut unmasked = Vec::new();
+			ws::encode_ws_packet(&mut unmasked, &packets[4]);
+			seeds.push(unmasked);
+			seeds
+		}
+
+		"http" => vec![
+			b"GET / HTTP/1.1\r\nHost: 127.0.0.1:1337\r\nConnection: Upgrade\r\nPragma: no-cache\r\n\
+				Cache-Control: no-cache\r\nUpgrade: websocket\r\nOrigin: http://127.0.0.1:1337\r\n\
+				Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+				Sec-WebSocket-Protocol: wire.v1, binary\r\n\
+				Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n".to_vec(),
+
+			b"GET / HTTP/1.1\r\nHost: 127.0.0.1:1337\r\nConnection: keep-alive\r\n\
+				User-Agent: Mozilla/5.0 (X11; Linux x86_64)\r\nAccept: text/html,*/*;q=0.8\r\n\r\n".to_vec(),
+
+			b"GET /wsclient.js HTTP/1.1\r\nHost: 127.0.0.1:1337\r\nReferer: http://127.0.0.1:1337/\r\n\
+				Accept-Encoding: gzip, deflate, br;q=0.9\r\nIf-None-Match: W/\"0123456789abcdef\"\r\n\
+				If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n".to_vec(),
+
+			b"PUT /x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello".to_vec(),
+
+			b"POST /submit?name=a%20b&x=1 HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+				5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n".to_vec(),
+
+			b"GET http://localhost/a/../b HTTP/1.0\r\n\r\n".to_vec(),
+
+			"GET /caf\u{e9}/\u{2713}?q=\u{65e5}\u{672c}+%E2%9C%93 HTTP/1.1\r\nHost: h\u{f4}st\r\n\
+				User-Agent: caf\u{e9} \u{1f600}\r\nAccept-Language: fr;q=0.9, \u{65e5}\u{672c};q=0.5\r\n\r\n".as_bytes().to_vec(),
+
+			"GET HTTPS://\u{e9}xample.com/\u{e9} HTTP/1.1\r\nOrigin: http://\u{e9}xample.com\r\n\
+				Upgrade: websocket\r\nSec-WebSocket-Protocol: wire.v1, \u{2713}\r\n\r\n".as_bytes().to_vec(),
+		],
+
+		_ => panic!("No seeds for {}", target),
+	}
+}
+
+// Masks a frame the way a client would send it, with a fixed key so the seeds don't change
+fn masked(mut frame: Vec<u8>) -> Vec<u8> {
+	let key = [0x37, 0xfa, 0x21, 0x3d];
+	let header_len = match frame[1] & 0x7F { 126 => 4, 127 => 10, _ => 2 };
+
+	frame[1] |= 0x80;
+	for (i, b) in frame[header_len..].iter_mut().enumerate() {
+		*b ^= key[i % 4];
+	}
+
+	frame.splice(header_len..header_len, key.iter().cloned());
+	frame
+}
+
+#[test]
+#[ignore]
+fn generate_seed_corpus() {
+	for &(name, _) in TARGETS {
+		let dir = data_dir("corpus", name);
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		for seed in seed_corpus(name) {
+			fs::write(dir.join(file_name(&seed)), &seed).unwrap();
+		}
+	}
+}
+
+#[test]
+fn fuzz_packet_parse() {
+	fuzz("packet");
+}
+
+#[test]
+fn fuzz_decode_ws_packet() {
+	fuzz("frame");
+}
+
+#[test]
+fn fuzz_http_request_parse() {
+	fuzz("http");
+}
+
+#[test]
+fn fuzz_regressions() {
+	for &(name, target) in TARGETS {
+		for (path, input) in load_dir(&data_dir("regressions", name)) {
+			if panic::catch_unwind(|| target(&input)).is_err() {
+				panic!("{} panics again on {}", name, path.display());
+			}
+		}
+	}
+}
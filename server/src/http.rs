@@ -1,14 +1,36 @@
+// Request exposes all of what it parses, even the parts neither server reads yet
+#![allow(dead_code)]
+
 extern crate std;
 
-use std::net::TcpStream;
 use std::io;
+use std::str;
 use std::option::Option;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::time::{SystemTime, Duration, UNIX_EPOCH};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Method {
+	Get,
+	Head,
+	Post,
+	Put,
+	Delete,
+	Options,
+}
 
 #[derive(Debug)]
 pub struct Request<'a> {
+	method: Method,
 	uri: &'a str,
-	fields: HashMap<&'a str, &'a str>,
+	path: &'a str,
+	query: Vec<(String, String)>,
+	version: &'a str,
+
+	// In the order they arrived, so repeated headers keep every value
+	fields: Vec<(&'a str, &'a str)>,
+	body: Cow<'a, [u8]>,
 }
 
 #[derive(Debug)]
@@ -18,63 +40,365 @@ pub struct Response<'a> {
 	body: Option<&'a [u8]>,
 }
 
+impl Method {
+	fn parse(method: &str) -> Option<Method> {
+		match method {
+			"GET" => Some(Method::Get),
+			"HEAD" => Some(Method::Head),
+			"POST" => Some(Method::Post),
+			"PUT" => Some(Method::Put),
+			"DELETE" => Some(Method::Delete),
+			"OPTIONS" => Some(Method::Options),
+			_ => None
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match *self {
+			Method::Get => "GET",
+			Method::Head => "HEAD",
+			Method::Post => "POST",
+			Method::Put => "PUT",
+			Method::Delete => "DELETE",
+			Method::Options => "OPTIONS",
+		}
+	}
+}
+
 impl<'a> Request<'a> {
-	pub fn parse(data: &'a str) -> Result<Request<'a>, &str> {
-		let header_end = data.split("\r\n\r\n").next().unwrap();
-		let mut lines = header_end.split_terminator("\r\n");
-		let reqline = lines.next().unwrap_or("");
+	// Parses a whole request, body included. Anything after it is ignored
+	pub fn parse(data: &'a [u8]) -> Result<Request<'a>, &'static str> {
+		match Request::decode(data)? {
+			Some((request, _)) => Ok(request),
+			None => Err("Incomplete request"),
+		}
+	}
 
-		let mut reqlineels = reqline.split_whitespace();
+	// Returns the request and how many bytes of data it took up,
+	// or None if the header or body haven't all arrived yet
+	pub fn decode(data: &'a [u8]) -> Result<Option<(Request<'a>, usize)>, &'static str> {
+		let header_end = match find(data, b"\r\n\r\n") {
+			Some(pos) => pos,
+			None => return Ok(None)
+		};
 
-		if reqlineels.next().unwrap_or("") != "GET" {
-			if cfg!(debug_requests) {
-				println!("{}", data);
-			}
-			return Err("Non-GET requests not supported");
+		let header = str::from_utf8(&data[..header_end])
+			.map_err(|_| "Non utf8 data encountered")?;
+
+		let mut lines = header.split("\r\n");
+		let reqline = lines.next().unwrap();
+		let reqlineels = reqline.split_whitespace().collect::<Vec<_>>();
+
+		if reqlineels.len() != 3 {
+			return Err("Malformed request line");
 		}
 
-		let requri = reqlineels.next().unwrap_or("");
-		let version = reqlineels.next().unwrap_or("");
+		let method = Method::parse(reqlineels[0]).ok_or("Unsupported method")?;
+		let uri = reqlineels[1];
+		let version = reqlineels[2];
 
 		if version != "HTTP/1.0" && version != "HTTP/1.1" {
-			if cfg!(debug_requests) {
-				println!("{}", data);
-			}
 			return Err("Invalid HTTP version");
 		}
 
-		let mut fields = HashMap::new();
+		let (path, query) = split_uri(uri)?;
+
+		let mut fields = Vec::new();
 
 		for line in lines {
-			let mut line = line.splitn(2, ":").map(|s| s.trim());
+			// Folded headers were deprecated by RFC 7230, and no client sends them
+			if line.starts_with(' ') || line.starts_with('\t') {
+				return Err("Folded headers not supported");
+			}
+
+			let mut line = line.splitn(2, ':');
 			let key = line.next().unwrap();
-			let value = match line.next() {
-				Some(v) => v,
-				None => continue
-			};
+			let value = line.next().ok_or("Malformed header")?;
 
-			fields.insert(key, value);
+			if key.is_empty() || key.contains(|c: char| c.is_whitespace()) {
+				return Err("Malformed header");
+			}
+
+			fields.push((key, value.trim()));
 		}
 
-		Ok(Request {
-			uri: requri,
+		let mut request = Request {
+			method: method,
+			uri: uri,
+			path: path,
+			query: parse_query(query)?,
+			version: version,
 			fields: fields,
-		})
+			body: Cow::Borrowed(&[]),
+		};
+
+		let body_start = header_end + 4;
+		let rest = &data[body_start..];
+
+		let body_len = match request.body_framing()? {
+			BodyFraming::None => 0,
+
+			BodyFraming::Length(len) => {
+				if rest.len() < len { return Ok(None) }
+				request.body = Cow::Borrowed(&rest[..len]);
+				len
+			}
+
+			BodyFraming::Chunked => match decode_chunked(rest)? {
+				Some((body, len)) => {
+					request.body = Cow::Owned(body);
+					len
+				}
+
+				None => return Ok(None)
+			}
+		};
+
+		Ok(Some((request, body_start + body_len)))
+	}
+
+	fn body_framing(&self) -> Result<BodyFraming, &'static str> {
+		let encodings = self.get_all("Transfer-Encoding");
+		let lengths = self.get_all("Content-Length");
+
+		// A request with both could be read differently by a proxy in front of the server
+		if !encodings.is_empty() && !lengths.is_empty() {
+			return Err("Both Content-Length and Transfer-Encoding given");
+		}
+
+		if !encodings.is_empty() {
+			let codings = encodings.iter()
+				.flat_map(|e| e.split(','))
+				.map(str::trim)
+				.filter(|e| !e.is_empty())
+				.collect::<Vec<_>>();
+
+			if codings.len() != 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+				return Err("Unsupported transfer encoding");
+			}
+
+			return Ok(BodyFraming::Chunked);
+		}
+
+		match lengths.split_first() {
+			Some((first, rest)) => {
+				// Repeats are allowed, but only if they all agree
+				if rest.iter().any(|l| l != first) {
+					return Err("Conflicting Content-Length");
+				}
+
+				// Checked by hand first, since parse allows a leading +
+				if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+					return Err("Invalid Content-Length");
+				}
+
+				first.parse().map(BodyFraming::Length).map_err(|_| "Invalid Content-Length")
+			}
+
+			None => Ok(BodyFraming::None)
+		}
+	}
+
+	pub fn method(&self) -> Method {
+		self.method
 	}
 
+	// The request target as it was sent, query string and all
 	pub fn uri(&self) -> &str {
 		self.uri
 	}
 
+	// Still percent encoded, since decoding it could change which file it refers to
+	pub fn path(&self) -> &str {
+		self.path
+	}
+
+	pub fn decoded_path(&self) -> Result<String, &'static str> {
+		percent_decode(self.path, false)
+	}
+
+	pub fn version(&self) -> &str {
+		self.version
+	}
+
+	// The first value of a percent decoded query parameter
+	pub fn query(&self, key: &str) -> Option<&str> {
+		self.query.iter()
+			.find(|&&(ref k, _)| k == key)
+			.map(|&(_, ref v)| v.as_str())
+	}
+
+	pub fn query_pairs(&self) -> &[(String, String)] {
+		&self.query
+	}
+
+	// Header names are case insensitive. Where a header is repeated this is the first value
 	pub fn get(&self, key: &str) -> Option<&str> {
-		if let Some(&val) = self.fields.get(&key) {
-			Some(val)
+		self.fields.iter()
+			.find(|&&(k, _)| k.eq_ignore_ascii_case(key))
+			.map(|&(_, v)| v)
+	}
+
+	pub fn get_all(&self, key: &str) -> Vec<&'a str> {
+		self.fields.iter()
+			.filter(|&&(k, _)| k.eq_ignore_ascii_case(key))
+			.map(|&(_, v)| v)
+			.collect()
+	}
+
+	pub fn body(&self) -> &[u8] {
+		&self.body
+	}
+
+	// Whether the client wants the connection kept open after the response.
+	// That's the default from HTTP/1.1, and has to be asked for before it
+	pub fn keep_alive(&self) -> bool {
+		let options = self.get_all("Connection").into_iter()
+			.flat_map(|c| c.split(','))
+			.map(str::trim)
+			.collect::<Vec<_>>();
+
+		if options.iter().any(|o| o.eq_ignore_ascii_case("close")) {
+			false
 		} else {
-			None
+			self.version == "HTTP/1.1" || options.iter().any(|o| o.eq_ignore_ascii_case("keep-alive"))
 		}
 	}
 }
 
+enum BodyFraming {
+	None,
+	Length(usize),
+	Chunked,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Splits a request target into its path and query string. Absolute URIs are allowed,
+// but only the path is kept, and * is only meaningful to OPTIONS
+fn split_uri(uri: &str) -> Result<(&str, &str), &'static str> {
+	let mut target = uri;
+
+	for scheme in &["http://", "https://"] {
+		// Compared as bytes, since the target can have multibyte characters in it
+		if target.as_bytes().get(..scheme.len()).map_or(false, |s| s.eq_ignore_ascii_case(scheme.as_bytes())) {
+			let rest = &target[scheme.len()..];
+			target = match rest.find('/') {
+				Some(pos) => &rest[pos..],
+				None => "/",
+			};
+		}
+	}
+
+	if !target.starts_with('/') && target != "*" {
+		return Err("Invalid request target");
+	}
+
+	match target.find('?') {
+		Some(pos) => Ok((&target[..pos], &target[pos+1..])),
+		None => Ok((target, "")),
+	}
+}
+
+fn parse_query(query: &str) -> Result<Vec<(String, String)>, &'static str> {
+	query.split('&')
+		.filter(|p| !p.is_empty())
+		.map(|pair| {
+			let mut pair = pair.splitn(2, '=');
+			let key = percent_decode(pair.next().unwrap(), true)?;
+			let value = percent_decode(pair.next().unwrap_or(""), true)?;
+			Ok((key, value))
+		})
+		.collect()
+}
+
+// Query strings are form encoded, where + is a space as well as %20
+fn percent_decode(s: &str, form: bool) -> Result<String, &'static str> {
+	fn hex_value(b: u8) -> Option<u8> {
+		(b as char).to_digit(16).map(|d| d as u8)
+	}
+
+	let bytes = s.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+
+	while i < bytes.len() {
+		match bytes[i] {
+			b'%' => {
+				let hi = bytes.get(i+1).cloned().and_then(hex_value);
+				let lo = bytes.get(i+2).cloned().and_then(hex_value);
+
+				match (hi, lo) {
+					(Some(hi), Some(lo)) => decoded.push(hi << 4 | lo),
+					_ => return Err("Invalid percent encoding"),
+				}
+
+				i += 3;
+			}
+
+			b'+' if form => { decoded.push(b' '); i += 1; }
+			b => { decoded.push(b); i += 1; }
+		}
+	}
+
+	String::from_utf8(decoded).map_err(|_| "Invalid percent encoding")
+}
+
+// Returns the body and how many bytes of data it took up, or None if it hasn't all arrived.
+// Chunk extensions and trailers are skipped
+fn decode_chunked(data: &[u8]) -> Result<Option<(Vec<u8>, usize)>, &'static str> {
+	let mut body = Vec::new();
+	let mut pos = 0;
+
+	loop {
+		let line_end = match find(&data[pos..], b"\r\n") {
+			Some(len) => pos + len,
+			None => return Ok(None)
+		};
+
+		let size = str::from_utf8(&data[pos..line_end]).ok()
+			.and_then(|line| line.split(';').next())
+			.map(str::trim)
+			.unwrap_or("");
+
+		if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+			return Err("Invalid chunk size");
+		}
+
+		let size = usize::from_str_radix(size, 16).map_err(|_| "Invalid chunk size")?;
+		pos = line_end + 2;
+
+		if size == 0 { break }
+
+		if data.len() - pos < size || data.len() - pos - size < 2 {
+			return Ok(None);
+		}
+
+		if &data[pos+size .. pos+size+2] != b"\r\n" {
+			return Err("Malformed chunk");
+		}
+
+		body.extend_from_slice(&data[pos .. pos+size]);
+		pos += size + 2;
+	}
+
+	loop {
+		let line_end = match find(&data[pos..], b"\r\n") {
+			Some(len) => pos + len,
+			None => return Ok(None)
+		};
+
+		let last = line_end == pos;
+		pos = line_end + 2;
+
+		if last { break }
+	}
+
+	Ok(Some((body, pos)))
+}
+
 impl<'a> Response<'a> {
 	pub fn new(status: &'a str) -> Response<'a> {
 		Response {
@@ -92,9 +416,8 @@ impl<'a> Response<'a> {
 		self.body = Some(body); // once told me
 	}
 
-	pub fn write_to_stream(&self, stream: &mut TcpStream) -> io::Result<()> {
-		use std::io::Write;
-
+	// Content-Length is filled in from the body, unless it's been set already
+	pub fn write_head_to_stream<W: io::Write>(&self, stream: &mut W) -> io::Result<()> {
 		let it = std::iter::once(self.status_line.to_string());
 		let fieldit = self.fields.iter().map(|(k, v)| format!("{}: {}", k, v));
 		let mut response_str = it.chain(fieldit)
@@ -104,9 +427,19 @@ impl<'a> Response<'a> {
 				acc
 			});
 
+		if let Some(ref body) = self.body {
+			if !self.fields.keys().any(|k| k.eq_ignore_ascii_case("Content-Length")) {
+				response_str.push_str(&format!("Content-Length: {}\r\n", body.len()));
+			}
+		}
+
 		response_str.push_str("\r\n");
 
-		stream.write_all(response_str.as_bytes())?;
+		stream.write_all(response_str.as_bytes())
+	}
+
+	pub fn write_to_stream<W: io::Write>(&self, stream: &mut W) -> io::Result<()> {
+		self.write_head_to_stream(stream)?;
 
 		if let Some(ref body) = self.body {
 			stream.write_all(&body)?;
@@ -116,6 +449,117 @@ impl<'a> Response<'a> {
 	}
 }
 
+// Splits an Accept style header, which can be repeated, into its values and their weights.
+// Weights are in thousandths, so 1 is 1000. Values with a malformed weight are left out
+pub fn weighted_list<'a>(headers: &[&'a str]) -> Vec<(&'a str, u16)> {
+	headers.iter()
+		.flat_map(|h| h.split(','))
+		.filter_map(|item| {
+			let mut params = item.split(';').map(str::trim);
+			let value = params.next().unwrap();
+			if value.is_empty() { return None }
+
+			let mut weight = Some(1000);
+			for param in params {
+				let mut kv = param.splitn(2, '=').map(str::trim);
+				if kv.next().unwrap().eq_ignore_ascii_case("q") {
+					weight = kv.next().and_then(parse_qvalue);
+				}
+			}
+
+			weight.map(|w| (value, w))
+		})
+		.collect()
+}
+
+// At most three decimal places, and no more than 1
+fn parse_qvalue(q: &str) -> Option<u16> {
+	let (int, frac) = match q.find('.') {
+		Some(pos) => (&q[..pos], &q[pos+1..]),
+		None => (q, ""),
+	};
+
+	if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+		return None;
+	}
+
+	let thousandths = format!("{:0<3}", frac).parse::<u16>().unwrap();
+
+	match int {
+		"0" => Some(thousandths),
+		"1" if thousandths == 0 => Some(1000),
+		_ => None
+	}
+}
+
+const WEEKDAYS: &[&str] = &["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: &[&str] = &["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// Formats a time the way Last-Modified and Date want it, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format_date(time: SystemTime) -> String {
+	let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+	let days = (secs / 86400) as i64;
+	let (year, month, day) = civil_from_days(days);
+	let secs = secs % 86400;
+
+	// Day 0 was a Thursday
+	format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", WEEKDAYS[(days % 7) as usize], day, MONTHS[month as usize - 1],
+		year, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// Only the format above is accepted. RFC 7231 lets senders use two older ones, but nothing does
+pub fn parse_date(date: &str) -> Option<SystemTime> {
+	let parts = date.split_whitespace().collect::<Vec<_>>();
+	if parts.len() != 6 || !parts[0].ends_with(',') || parts[5] != "GMT" {
+		return None;
+	}
+
+	fn number(s: &str, digits: usize) -> Option<i64> {
+		if s.len() != digits || !s.bytes().all(|b| b.is_ascii_digit()) { return None }
+		s.parse().ok()
+	}
+
+	let day = number(parts[1], 2)?;
+	let month = MONTHS.iter().position(|&m| m == parts[2])? as i64 + 1;
+	let year = number(parts[3], 4)?;
+
+	let time = parts[4].split(':').collect::<Vec<_>>();
+	if time.len() != 3 { return None }
+
+	let (hour, min, sec) = (number(time[0], 2)?, number(time[1], 2)?, number(time[2], 2)?);
+	if day < 1 || day > 31 || hour > 23 || min > 59 || sec > 60 || year < 1970 {
+		return None;
+	}
+
+	let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec;
+	Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+// Conversions between days since 1970 and the proleptic gregorian calendar,
+// from http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+	let z = days + 719468;
+	let era = if z >= 0 { z } else { z - 146096 } / 146097;
+	let doe = z - era * 146097;
+	let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+	let doy = doe - (365*yoe + yoe/4 - yoe/100);
+	let mp = (5*doy + 2) / 153;
+	let day = doy - (153*mp + 2)/5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = if year >= 0 { year } else { year - 399 } / 400;
+	let yoe = year - era * 400;
+	let mp = if month > 2 { month - 3 } else { month + 9 };
+	let doy = (153*mp + 2)/5 + day - 1;
+	let doe = yoe * 365 + yoe/4 - yoe/100 + doy;
+	era * 146097 + doe - 719468
+}
+
 // GET / HTTP/1.1
 // Host: 0.0.0.0:9001
 // Connection: Upgrade
@@ -135,3 +579,176 @@ impl<'a> Response<'a> {
 // Upgrade: websocket
 // Connection: Upgrade
 // Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn methods_and_targets() {
+		let request = Request::parse(b"DELETE /things/12?force=yes&note=a%20b+c HTTP/1.1\r\n\r\n").unwrap();
+		assert!(request.method() == Method::Delete);
+		assert!(request.uri() == "/things/12?force=yes&note=a%20b+c");
+		assert!(request.path() == "/things/12");
+		assert!(request.query("force") == Some("yes"));
+		assert!(request.query("note") == Some("a b c"));
+		assert!(request.query("missing") == None);
+		assert!(request.query_pairs() == &[("force".to_string(), "yes".to_string()), ("note".to_string(), "a b c".to_string())]);
+
+		// Only query strings are form encoded, so an encoded + stays one and a bare + in the path isn't a space
+		let request = Request::parse(b"GET /a+b?x=1%2B1+%3D+2&&flag&=empty HTTP/1.1\r\n\r\n").unwrap();
+		assert!(request.query("x") == Some("1+1 = 2"));
+		assert!(request.query("flag") == Some(""));
+		assert!(request.query("") == Some("empty"));
+		assert!(request.decoded_path() == Ok("/a+b".to_string()));
+
+		let request = Request::parse(b"GET /a%20b+c/%2e%2e HTTP/1.1\r\n\r\n").unwrap();
+		assert!(request.decoded_path() == Ok("/a b+c/..".to_string()));
+
+		let request = Request::parse(b"OPTIONS * HTTP/1.1\r\n\r\n").unwrap();
+		assert!(request.method() == Method::Options);
+		assert!(request.path() == "*");
+
+		let request = Request::parse(b"HEAD http://localhost:8000/index.html HTTP/1.0\r\n\r\n").unwrap();
+		assert!(request.method() == Method::Head);
+		assert!(request.path() == "/index.html");
+		assert!(request.version() == "HTTP/1.0");
+
+		assert!(Request::parse(b"PATCH / HTTP/1.1\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET / HTTP/2.0\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET index.html HTTP/1.1\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET / extra HTTP/1.1\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET /?a=%zz HTTP/1.1\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET /?a=%ff HTTP/1.1\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET /%zz HTTP/1.1\r\n\r\n").unwrap().decoded_path().is_err());
+	}
+
+	#[test]
+	fn multibyte_targets_dont_panic() {
+		assert!(Request::parse("GET /aaaaaé HTTP/1.1\r\n\r\n".as_bytes()).unwrap().path() == "/aaaaaé");
+		assert!(Request::parse("GET /ééé/é HTTP/1.1\r\n\r\n".as_bytes()).unwrap().path() == "/ééé/é");
+		assert!(Request::parse("GET é HTTP/1.1\r\n\r\n".as_bytes()).is_err());
+		assert!(Request::parse("GET httpé://x/ HTTP/1.1\r\n\r\n".as_bytes()).is_err());
+		assert!(Request::parse("GET HTTPS://hôst/a HTTP/1.1\r\n\r\n".as_bytes()).unwrap().path() == "/a");
+	}
+
+	#[test]
+	fn headers_are_case_insensitive_and_can_repeat() {
+		let request = Request::parse(b"GET / HTTP/1.1\r\n\
+			Host: localhost\r\n\
+			accept-encoding: gzip\r\n\
+			ACCEPT-ENCODING:deflate  \r\n\r\n").unwrap();
+
+		assert!(request.get("host") == Some("localhost"));
+		assert!(request.get("Accept-Encoding") == Some("gzip"));
+		assert!(request.get_all("Accept-Encoding") == vec!["gzip", "deflate"]);
+		assert!(request.get_all("Origin").is_empty());
+
+		assert!(Request::parse(b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n").is_err());
+		assert!(Request::parse(b"GET / HTTP/1.1\r\nHost: localhost\r\n  folded\r\n\r\n").is_err());
+	}
+
+	#[test]
+	fn keep_alive_depends_on_version() {
+		assert!(Request::parse(b"GET / HTTP/1.1\r\n\r\n").unwrap().keep_alive());
+		assert!(!Request::parse(b"GET / HTTP/1.1\r\nConnection: Upgrade, close\r\n\r\n").unwrap().keep_alive());
+		assert!(!Request::parse(b"GET / HTTP/1.0\r\n\r\n").unwrap().keep_alive());
+		assert!(Request::parse(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().keep_alive());
+	}
+
+	#[test]
+	fn responses_with_bodies_have_a_length() {
+		let mut res = Response::new("HTTP/1.1 200 OK");
+		res.set_body(b"hello");
+
+		let mut head = Vec::new();
+		res.write_head_to_stream(&mut head).unwrap();
+		assert!(head == b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+
+		let mut full = Vec::new();
+		res.write_to_stream(&mut full).unwrap();
+		assert!(full == b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+
+		let mut upgrade = Vec::new();
+		Response::new("HTTP/1.1 101 Switching Protocols").write_to_stream(&mut upgrade).unwrap();
+		assert!(upgrade == b"HTTP/1.1 101 Switching Protocols\r\n\r\n");
+	}
+
+	#[test]
+	fn weighted_lists() {
+		assert!(weighted_list(&["gzip, deflate;q=0.5", "br;q=0.123 , identity; q=0"])
+			== vec![("gzip", 1000), ("deflate", 500), ("br", 123), ("identity", 0)]);
+
+		assert!(weighted_list(&["*;Q=1.000", "a;q=1.", "b;q=0.", "c;level=2;q=0.25"])
+			== vec![("*", 1000), ("a", 1000), ("b", 0), ("c", 250)]);
+
+		// Malformed weights drop the value rather than guess at it
+		assert!(weighted_list(&["a;q=1.5, b;q=0.0001, c;q=-1, d;q=, e;q=2, f;q=abc, g"]) == vec![("g", 1000)]);
+		assert!(weighted_list(&[""]).is_empty());
+		assert!(weighted_list(&[]).is_empty());
+	}
+
+	#[test]
+	fn dates_round_trip() {
+		let rfc_example = UNIX_EPOCH + Duration::from_secs(784111777);
+		assert!(format_date(rfc_example) == "Sun, 06 Nov 1994 08:49:37 GMT");
+		assert!(parse_date("Sun, 06 Nov 1994 08:49:37 GMT") == Some(rfc_example));
+
+		assert!(format_date(UNIX_EPOCH) == "Thu, 01 Jan 1970 00:00:00 GMT");
+		assert!(parse_date("Tue, 29 Feb 2000 23:59:59 GMT") == Some(UNIX_EPOCH + Duration::from_secs(951868799)));
+
+		for &secs in &[0, 59, 86399, 86400, 951782400, 1700000000, 4102444800] {
+			let time = UNIX_EPOCH + Duration::from_secs(secs);
+			assert!(parse_date(&format_date(time)) == Some(time), "{}", secs);
+		}
+
+		assert!(parse_date("Sunday, 06-Nov-94 08:49:37 GMT").is_none());
+		assert!(parse_date("Sun Nov  6 08:49:37 1994").is_none());
+		assert!(parse_date("Sun, 06 Nov 1994 08:49:37 UTC").is_none());
+		assert!(parse_date("Sun, 06 Foo 1994 08:49:37 GMT").is_none());
+		assert!(parse_date("Sun, 06 Nov 1994 8:49:37 GMT").is_none());
+	}
+
+	#[test]
+	fn content_length_bodies() {
+		let data = b"POST /submit HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET";
+		let (request, len) = Request::decode(data).unwrap().unwrap();
+		assert!(request.body() == b"hello");
+		assert!(&data[len..] == b"GET");
+
+		// Nothing is returned until all of the body is there
+		assert!(Request::decode(&data[..data.len() - 4]).unwrap().is_none());
+		assert!(Request::decode(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n").unwrap().is_none());
+
+		assert!(Request::parse(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi").is_ok());
+		assert!(Request::parse(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nhi!").is_err());
+		assert!(Request::parse(b"POST / HTTP/1.1\r\nContent-Length: -2\r\n\r\n").is_err());
+		assert!(Request::parse(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n").is_err());
+	}
+
+	#[test]
+	fn chunked_bodies() {
+		let data = b"PUT /file HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+			5\r\nhello\r\n\
+			7;ext=1\r\n, world\r\n\
+			0\r\n\
+			Trailer: ignored\r\n\r\nGET";
+
+		let (request, len) = Request::decode(data).unwrap().unwrap();
+		assert!(request.body() == b"hello, world");
+		assert!(&data[len..] == b"GET");
+
+		for end in 0..data.len() - 3 {
+			assert!(Request::decode(&data[..end]).unwrap().is_none());
+		}
+
+		let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n";
+		assert!(Request::parse(format!("{}\r\n0\r\n\r\n", chunked).as_bytes()).unwrap().body().is_empty());
+		assert!(Request::parse(format!("{}\r\nx\r\n\r\n", chunked).as_bytes()).is_err());
+		assert!(Request::parse(format!("{}\r\n2\r\nabc\r\n0\r\n\r\n", chunked).as_bytes()).is_err());
+		assert!(Request::parse(format!("{}\r\nfffffffffffffffff\r\n", chunked).as_bytes()).is_err());
+		assert!(Request::parse(format!("{}Content-Length: 5\r\n\r\n0\r\n\r\n", chunked).as_bytes()).is_err());
+		assert!(Request::parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").is_err());
+	}
+}
@@ -1,8 +1,16 @@
 #![feature(ord_max_min)]
 
+mod assets;
+mod config;
 mod connections;
+#[cfg(test)]
+mod conformance;
+mod deflate;
 mod fileserver;
+#[cfg(test)]
+mod fuzz;
 mod http;
+mod tls;
 mod ws;
 
 #[macro_use]
@@ -11,19 +19,29 @@ extern crate common;
 extern crate sha1;
 extern crate base64;
 extern crate flate2;
+extern crate mio;
+extern crate rustls;
+extern crate rustls_pemfile;
 
-use std::net::{TcpStream, TcpListener};
-use std::io::Read;
+use std::io;
 use std::sync::mpsc;
 use std::thread;
-use std::time;
+
+use mio::{Poll, Events, Token, Ready, PollOpt, Registration, SetReadiness};
 
 use common::*;
-use connections::ConnectionID;
+use connections::{ConnectionID, ConnectionManager};
+use config::Config;
+use tls::{TlsAcceptor, BlockingStream};
+
+const CONFIG_PATH: &str = "wire.conf";
+
+// Connection tokens are their ConnectionIDs, so these are kept well out of the way
+const LISTENER_TOKEN: Token = Token(std::usize::MAX - 1);
+const MESSAGE_TOKEN: Token = Token(std::usize::MAX - 2);
 
-// main thread, sim -> network thread
+// sim -> network thread
 enum NetworkMessage {
-	NewConnection(TcpStream),
 	NewSession(ConnectionID, u32),
 	AuthSuccess(ConnectionID, u32),
 	AuthFail(ConnectionID),
@@ -37,105 +55,145 @@ enum SimulationMessage {
 	RequestWorldState(ConnectionID),
 }
 
+// Wakes the network loop whenever it's sent a message
+#[derive(Clone)]
+struct NetworkSender {
+	tx: mpsc::Sender<NetworkMessage>,
+	waker: SetReadiness,
+}
+
+impl NetworkSender {
+	fn send(&self, msg: NetworkMessage) {
+		self.tx.send(msg).unwrap();
+		self.waker.set_readiness(Ready::readable()).unwrap();
+	}
+}
+
 fn main() {
 	println!("Is Hosted:      {}", cfg!(hosted));
 	println!("Public address: {}", env!("PUBLIC_ADDRESS"));
 
-	let listener = TcpListener::bind("0.0.0.0:1337").unwrap();
-	let fs_listener = TcpListener::bind("0.0.0.0:8080").unwrap();
-
-	thread::spawn(move || fileserver::start(fs_listener));
+	let config = Config::load(CONFIG_PATH);
 
-	let (main_tx, net_rx) = mpsc::channel::<NetworkMessage>();
-	let (net_tx, sim_rx) = mpsc::channel::<SimulationMessage>();
-	let sim_tx = main_tx.clone();
+	let tls = match (config.tls_cert_path.as_ref(), config.tls_key_path.as_ref()) {
+		(Some(cert_path), Some(key_path)) => match TlsAcceptor::load(cert_path, key_path) {
+			Ok(acceptor) => Some(acceptor),
+			Err(e) => {
+				println!("Failed to set up TLS: {}", e);
+				return;
+			}
+		},
 
-	let connection_thd = thread::spawn(move || network_loop(net_rx, net_tx));
-	let simulation_thd = thread::spawn(move || sim_loop(sim_tx, sim_rx));
+		(None, None) => None,
 
-	for stream in listener.incoming() {
-		match stream {
-			Ok(mut stream) => {
-				let mut buf = [0u8; 1024];
+		_ => {
+			println!("Failed to set up TLS: tls_cert_path and tls_key_path must be set together");
+			return;
+		}
+	};
 
-				// TODO: poll or async instead of block until timeout
-				stream.set_read_timeout(Some(time::Duration::from_millis(500))).expect("set_read_timeout failed");
+	println!("TLS:            {}", tls.is_some());
+	let fs_config = config.clone();
+	let connections = ConnectionManager::new(config, tls);
 
-				let size = match stream.read(&mut buf) {
-					Ok(s) => s, Err(_) => continue
-				};
+	// Websockets and plain http share a port, anything that isn't an upgrade goes to the file server
+	let listener = mio::net::TcpListener::bind(&"0.0.0.0:1337".parse().unwrap()).unwrap();
+	let (fs_tx, fs_rx) = mpsc::channel();
 
-				if size == 0 { continue }
+	thread::spawn(move || fileserver::start(fs_rx, fs_config));
 
-				let data = std::str::from_utf8(&buf[0..size]);
-				if !data.is_ok() {
-					println!("Error parsing request: Non utf8 data encountered");
-					continue;
-				}
+	let (registration, waker) = Registration::new2();
+	let (main_tx, net_rx) = mpsc::channel::<NetworkMessage>();
+	let (net_tx, sim_rx) = mpsc::channel::<SimulationMessage>();
+	let sim_tx = NetworkSender{ tx: main_tx, waker: waker.clone() };
 
-				match http::Request::parse(data.unwrap()) {
-					Ok(header) => {
-						if header.get("Upgrade") != Some("websocket") {
-							continue;
-						}
+	let simulation_thd = thread::spawn(move || sim_loop(sim_tx, sim_rx));
 
-						stream.set_read_timeout(None).expect("set_read_timeout failed");
+	network_loop(connections, listener, net_rx, registration, waker, net_tx, fs_tx);
 
-						match ws::init_websocket_connection(&mut stream, &header) {
-							Ok(_) => main_tx.send(NetworkMessage::NewConnection(stream)).unwrap(),
-							Err(e) => println!("Error initialising connection: {}", e)
-						}
-					},
+	simulation_thd.join().unwrap();
+}
 
-					Err(e) => {
-						println!("Error parsing request: {}", e);
-					},
-				}
-			},
+fn network_loop(mut connections: ConnectionManager, listener: mio::net::TcpListener,
+	rx: mpsc::Receiver<NetworkMessage>, rx_registration: Registration, rx_readiness: SetReadiness,
+	tx: mpsc::Sender<SimulationMessage>, fs_tx: mpsc::Sender<(Box<dyn BlockingStream>, Vec<u8>)>) {
 
-			Err(e) => { println!("Connection failed: {}", e); }
-		}
-	}
+	let poll = Poll::new().expect("Failed to create poll");
+	poll.register(&listener, LISTENER_TOKEN, Ready::readable(), PollOpt::edge()).expect("Failed to register listener");
+	poll.register(&rx_registration, MESSAGE_TOKEN, Ready::readable(), PollOpt::edge()).expect("Failed to register message queue");
 
-	connection_thd.join().unwrap();
-	simulation_thd.join().unwrap();
-}
+	let mut events = Events::with_capacity(256);
 
-fn network_loop(rx: mpsc::Receiver<NetworkMessage>, tx: mpsc::Sender<SimulationMessage>) {
-	let mut connections = connections::ConnectionManager::new();
-	let mut packet_buffer = [0u8; 8<<10];
+	let mut read_buffer = [0u8; 8<<10];
 
 	let mut packet_queue: Vec<(Option<ConnectionID>, Packet)> = Vec::new();
 
 	'main: loop {
-		while let Some(msg) = rx.try_recv().ok() {
-			use NetworkMessage as NM;
-
-			match msg {
-				NM::NewConnection(stream) => connections.register_connection(stream),
-				NM::NewSession(id, token) => {
-					if connections.notify_new_session(id) {
-						packet_queue.push((Some(id), Packet::NewSession(token)));
+		if let Err(e) = poll.poll(&mut events, connections.next_timeout()) {
+			if e.kind() != io::ErrorKind::Interrupted {
+				println!("Poll failed: {}", e);
+			}
+
+			continue;
+		}
+
+		for event in events.iter() {
+			match event.token() {
+				LISTENER_TOKEN => loop {
+					match listener.accept_std() {
+						Ok((stream, _)) => connections.register_connection(&poll, stream),
+						Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+						Err(e) => {
+							println!("Connection failed: {}", e);
+							break;
+						}
 					}
-				}
+				},
+
+				MESSAGE_TOKEN => {
+					let _ = rx_readiness.set_readiness(Ready::empty());
+
+					while let Some(msg) = rx.try_recv().ok() {
+						use NetworkMessage as NM;
+
+						match msg {
+							NM::NewSession(id, token) => {
+								if connections.notify_new_session(id) {
+									packet_queue.push((Some(id), Packet::NewSession(token)));
+								}
+							}
+
+							NM::AuthSuccess(id, token) => {
+								if connections.imbue_session(id, token) {
+									packet_queue.push((Some(id), Packet::AuthSuccessful(token)));
+								}
+							}
+
+							NM::AuthFail(id) => {
+								connections.notify_auth_fail(id);
+								packet_queue.push((Some(id), Packet::AuthFail));
+							}
+						}
+					}
+				},
+
+				Token(id) => {
+					let id = id as ConnectionID;
 
-				NM::AuthSuccess(id, token) => {
-					if connections.imbue_session(id, token) {
-						packet_queue.push((Some(id), Packet::AuthSuccessful(token)));
+					if event.readiness().is_readable() {
+						connections.handle_readable(id, &mut read_buffer);
 					}
-				}
 
-				NM::AuthFail(id) => {
-					connections.notify_auth_fail(id);
-					packet_queue.push((Some(id), Packet::AuthFail));
+					if event.readiness().is_writable() {
+						connections.handle_writable(id);
+					}
 				}
-
 			}
 		}
 
 		use SimulationMessage as SM;
 
-		while let Some((id, packet)) = connections.try_read(&mut packet_buffer) {
+		while let Some((id, packet)) = connections.try_read() {
 			match packet {
 				Packet::Debug(s) => {
 					println!("Debug ({}): {}", id, s);
@@ -150,16 +208,11 @@ fn network_loop(rx: mpsc::Receiver<NetworkMessage>, tx: mpsc::Sender<SimulationM
 			}
 		}
 
-		connections.flush();
-
-		while let Some(id) = connections.poll_new_sessions() {
-			tx.send(SM::RequestNewSession(id)).unwrap();
-		}
-
-		while let Some((id, token)) = connections.poll_auth_attempts() {
-			tx.send(SM::AttemptAuthSession(id, token)).unwrap();
+		while let Some(request) = connections.poll_http_requests(&poll) {
+			fs_tx.send(request).unwrap();
 		}
 
+		// Everything sent this tick is batched up per connection, and goes out on flush
 		for &(id, ref p) in &packet_queue {
 			if !p.is_valid_from_server() { continue }
 
@@ -172,47 +225,53 @@ fn network_loop(rx: mpsc::Receiver<NetworkMessage>, tx: mpsc::Sender<SimulationM
 
 		packet_queue.clear();
 
-		thread::sleep(time::Duration::from_millis(50));
+		connections.send_heartbeats();
+		connections.flush();
+
+		while let Some(id) = connections.poll_new_sessions() {
+			tx.send(SM::RequestNewSession(id)).unwrap();
+		}
+
+		while let Some((id, token)) = connections.poll_auth_attempts() {
+			tx.send(SM::AttemptAuthSession(id, token)).unwrap();
+		}
 	}
 }
 
 //////////////////////////////
 
-fn sim_loop(tx: mpsc::Sender<NetworkMessage>, rx: mpsc::Receiver<SimulationMessage>) {
+fn sim_loop(tx: NetworkSender, rx: mpsc::Receiver<SimulationMessage>) {
 	use NetworkMessage as NM;
 	use SimulationMessage as SM;
 
-	'main: loop {
-		while let Some(msg) = rx.try_recv().ok() {
-			match msg {
-				SM::RequestNewSession(con_id) => {
-					// Create new session
-					println!("New Session requested for {}", con_id);
-
-					let max_key = 3u32.pow(9);
-
-					let mut rng = thread_rng();
-					let random_key = rng.gen_range(0, max_key);
-					// TODO: not this
-					
-					tx.send(NM::NewSession(con_id, random_key)).unwrap();
-
-					// TODO: Test if con_id already associated with pending session and delete
-					// 	associate new session with connection and flag as pending
-					// 	remove flag and persist once authed
-				}
+	// Nothing happens in the simulation unless the network thread asks for it
+	for msg in rx.iter() {
+		match msg {
+			SM::RequestNewSession(con_id) => {
+				// Create new session
+				println!("New Session requested for {}", con_id);
 
-				SM::AttemptAuthSession(con_id, token) => {
-					// Just accept everything for now
-					tx.send(NM::AuthSuccess(con_id, token)).unwrap();
-				}
+				let max_key = 3u32.pow(9);
 
-				SM::RequestWorldState(con_id) => {
-					println!("Req world state ({})", con_id);
-				}
+				let mut rng = thread_rng();
+				let random_key = rng.gen_range(0, max_key);
+				// TODO: not this
+				
+				tx.send(NM::NewSession(con_id, random_key));
+
+				// TODO: Test if con_id already associated with pending session and delete
+				// 	associate new session with connection and flag as pending
+				// 	remove flag and persist once authed
+			}
+
+			SM::AttemptAuthSession(con_id, token) => {
+				// Just accept everything for now
+				tx.send(NM::AuthSuccess(con_id, token));
 			}
-		}
 
-		thread::sleep(time::Duration::from_millis(50));
+			SM::RequestWorldState(con_id) => {
+				println!("Req world state ({})", con_id);
+			}
+		}
 	}
-}
\ No newline at end of file
+}
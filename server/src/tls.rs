@@ -0,0 +1,347 @@
+use std::io::{self, Read, Write, BufReader};
+use std::fs::File;
+use std::net;
+use std::sync::Arc;
+use std::time::Duration;
+use mio::net::TcpStream;
+use rustls::{self, ServerConfig, ServerConnection, Certificate, PrivateKey};
+use rustls_pemfile;
+
+// Holds the certificate, and starts a server session for each accepted socket
+pub struct TlsAcceptor {
+	config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+	pub fn load(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+		let certs = read_pem_file(cert_path)?.into_iter()
+			.filter_map(|item| match item {
+				rustls_pemfile::Item::X509Certificate(cert) => Some(Certificate(cert)),
+				_ => None
+			})
+			.collect::<Vec<_>>();
+
+		if certs.is_empty() {
+			return Err(format!("No certificates found in '{}'", cert_path));
+		}
+
+		let key = read_pem_file(key_path)?.into_iter()
+			.filter_map(|item| match item {
+				rustls_pemfile::Item::RSAKey(key)
+				| rustls_pemfile::Item::PKCS8Key(key)
+				| rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+				_ => None
+			})
+			.next()
+			.ok_or_else(|| format!("No private key found in '{}'", key_path))?;
+
+		let config = ServerConfig::builder()
+			.with_safe_defaults()
+			.with_no_client_auth()
+			.with_single_cert(certs, key)
+			.map_err(|e| format!("Invalid certificate or key: {}", e))?;
+
+		Ok(TlsAcceptor { config: Arc::new(config) })
+	}
+
+	pub fn accept(&self, socket: Socket) -> Result<Stream, String> {
+		ServerConnection::new(self.config.clone())
+			.map(|session| Stream::Tls(socket, Box::new(session)))
+			.map_err(|e| format!("Failed to start TLS session: {}", e))
+	}
+}
+
+fn read_pem_file(path: &str) -> Result<Vec<rustls_pemfile::Item>, String> {
+	let file = File::open(path)
+		.map_err(|e| format!("Couldn't open '{}': {}", path, e))?;
+
+	rustls_pemfile::read_all(&mut BufReader::new(file))
+		.map_err(|e| format!("Couldn't read '{}': {}", path, e))
+}
+
+// A connection's socket, which is encrypted if the server has a certificate.
+// Both kinds are non-blocking, and report WouldBlock like a plain socket would
+pub enum Stream {
+	Plain(Socket),
+	Tls(Socket, Box<ServerConnection>),
+}
+
+// The socket the event loop reads and writes. mio can't hand its socket back on Windows,
+// so there a duplicate is kept from the start, for the file server to block on later
+pub struct Socket {
+	stream: TcpStream,
+
+	#[cfg(windows)]
+	duplicate: net::TcpStream,
+}
+
+// What the file server gets once a connection has been taken out of the event loop
+pub trait BlockingStream: Read + Write + Send {}
+impl<T: Read + Write + Send> BlockingStream for T {}
+
+impl Stream {
+	pub fn socket(&self) -> &TcpStream {
+		match *self {
+			Stream::Plain(ref socket) => &socket.stream,
+			Stream::Tls(ref socket, _) => &socket.stream,
+		}
+	}
+
+	// Anything already read or decrypted but not yet consumed stays with the stream.
+	// Reads fail with WouldBlock or TimedOut once nothing has arrived for read_timeout
+	pub fn into_blocking(self, read_timeout: Option<Duration>) -> io::Result<Box<dyn BlockingStream>> {
+		match self {
+			Stream::Plain(socket) => Ok(Box::new(socket.into_blocking(read_timeout)?)),
+			Stream::Tls(socket, session) => {
+				let socket = socket.into_blocking(read_timeout)?;
+				Ok(Box::new(rustls::StreamOwned::new(*session, socket)))
+			}
+		}
+	}
+}
+
+impl Read for Stream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let (socket, session) = match *self {
+			Stream::Plain(ref mut socket) => return socket.read(buf),
+			Stream::Tls(ref mut socket, ref mut session) => (socket, session),
+		};
+
+		loop {
+			match session.reader().read(buf) {
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+				result => return result,
+			}
+
+			// Nothing decrypted yet, so pull more records off the socket
+			if session.read_tls(socket)? == 0 {
+				return Ok(0);
+			}
+
+			if let Err(e) = session.process_new_packets() {
+				// Give the client a chance to find out what went wrong
+				let _ = write_tls(socket, session);
+
+				let what = if session.is_handshaking() { "TLS handshake failed" } else { "TLS error" };
+				return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", what, e)));
+			}
+
+			// The handshake might want a reply. If the socket is full it goes out on the next flush
+			match write_tls(socket, session) {
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+				result => result?,
+			}
+		}
+	}
+}
+
+impl Write for Stream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let (socket, session) = match *self {
+			Stream::Plain(ref mut socket) => return socket.write(buf),
+			Stream::Tls(ref mut socket, ref mut session) => (socket, session),
+		};
+
+		// Nothing more is encrypted until the last lot has been sent,
+		// otherwise the session would buffer everything and the send queue would never back up
+		write_tls(socket, session)?;
+
+		let len = session.writer().write(buf)?;
+
+		match write_tls(socket, session) {
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(len),
+			Err(e) => Err(e),
+			Ok(()) => Ok(len),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			Stream::Plain(ref mut socket) => socket.flush(),
+			Stream::Tls(ref mut socket, ref mut session) => write_tls(socket, session),
+		}
+	}
+}
+
+fn write_tls(socket: &mut Socket, session: &mut ServerConnection) -> io::Result<()> {
+	while session.wants_write() {
+		session.write_tls(socket)?;
+	}
+
+	Ok(())
+}
+
+impl Socket {
+	#[cfg(unix)]
+	pub fn new(socket: net::TcpStream) -> io::Result<Socket> {
+		Ok(Socket { stream: TcpStream::from_stream(socket)? })
+	}
+
+	#[cfg(windows)]
+	pub fn new(socket: net::TcpStream) -> io::Result<Socket> {
+		let duplicate = socket.try_clone()?;
+		Ok(Socket { stream: TcpStream::from_stream(socket)?, duplicate: duplicate })
+	}
+
+	#[cfg(unix)]
+	fn into_blocking(self, read_timeout: Option<Duration>) -> io::Result<net::TcpStream> {
+		use std::os::unix::io::{IntoRawFd, FromRawFd};
+
+		let socket = unsafe { net::TcpStream::from_raw_fd(self.stream.into_raw_fd()) };
+		socket.set_nonblocking(false)?;
+		socket.set_read_timeout(read_timeout)?;
+		Ok(socket)
+	}
+
+	// mio's socket is closed, and the duplicate carries on without it
+	#[cfg(windows)]
+	fn into_blocking(self, read_timeout: Option<Duration>) -> io::Result<net::TcpStream> {
+		let socket = self.duplicate;
+		socket.set_nonblocking(false)?;
+		socket.set_read_timeout(read_timeout)?;
+		Ok(socket)
+	}
+}
+
+impl Read for Socket {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.stream.read(buf) }
+}
+
+impl Write for Socket {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.stream.write(buf) }
+	fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::convert::TryFrom;
+	use std::thread;
+	use std::time::Duration;
+
+	const CERT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/selfsigned.crt");
+	const KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/selfsigned.key");
+
+	// Accepts one loopback connection, and wraps it the way the event loop would
+	fn accept_one() -> (Stream, net::TcpStream) {
+		let acceptor = TlsAcceptor::load(CERT_PATH, KEY_PATH).unwrap();
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (socket, _) = listener.accept().unwrap();
+
+		let stream = acceptor.accept(Socket::new(socket).unwrap()).unwrap();
+		(stream, client)
+	}
+
+	fn client_session() -> rustls::ClientConnection {
+		let mut roots = rustls::RootCertStore::empty();
+		for item in read_pem_file(CERT_PATH).unwrap() {
+			if let rustls_pemfile::Item::X509Certificate(cert) = item {
+				roots.add(&Certificate(cert)).unwrap();
+			}
+		}
+
+		let config = rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+
+		let name = rustls::ServerName::try_from("localhost").unwrap();
+		rustls::ClientConnection::new(Arc::new(config), name).unwrap()
+	}
+
+	// Spins on a non-blocking stream until it has exactly len bytes
+	fn read_exact_nonblocking(stream: &mut Stream, len: usize) -> io::Result<Vec<u8>> {
+		let mut data = vec![0u8; len];
+		let mut pos = 0;
+
+		while pos < len {
+			match stream.read(&mut data[pos..]) {
+				Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+				Ok(n) => pos += n,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+				Err(e) => return Err(e),
+			}
+		}
+
+		Ok(data)
+	}
+
+	#[test]
+	fn load_reports_missing_files() {
+		assert!(TlsAcceptor::load("no/such.crt", KEY_PATH).is_err());
+		assert!(TlsAcceptor::load(CERT_PATH, "no/such.key").is_err());
+
+		// A certificate isn't a key
+		assert!(TlsAcceptor::load(CERT_PATH, CERT_PATH).is_err());
+	}
+
+	#[test]
+	fn round_trip_over_loopback() {
+		let (mut server, client) = accept_one();
+
+		let client_thd = thread::spawn(move || {
+			let mut client = client;
+			let mut session = client_session();
+			let mut stream = rustls::Stream::new(&mut session, &mut client);
+
+			stream.write_all(b"ping").unwrap();
+
+			let mut response = [0u8; 4];
+			stream.read_exact(&mut response).unwrap();
+			response
+		});
+
+		assert_eq!(read_exact_nonblocking(&mut server, 4).unwrap(), b"ping");
+
+		let mut written = 0;
+		while written < 4 {
+			match server.write(&b"pong"[written..]) {
+				Ok(n) => written += n,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+				Err(e) => panic!("{}", e),
+			}
+		}
+
+		assert_eq!(&client_thd.join().unwrap(), b"pong");
+	}
+
+	#[test]
+	fn plaintext_client_fails_handshake() {
+		let (mut server, mut client) = accept_one();
+
+		client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+		let error = read_exact_nonblocking(&mut server, 1).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+		assert!(error.to_string().starts_with("TLS handshake failed"));
+	}
+
+	#[test]
+	fn blocking_stream_keeps_unread_data() {
+		let (mut server, client) = accept_one();
+
+		let client_thd = thread::spawn(move || {
+			let mut client = client;
+			let mut session = client_session();
+			let mut stream = rustls::Stream::new(&mut session, &mut client);
+
+			stream.write_all(b"GET / HTTP/1.1\r\n\r\nmore").unwrap();
+
+			let mut response = [0u8; 2];
+			stream.read_exact(&mut response).unwrap();
+			response
+		});
+
+		// Only read the request line, the way the event loop stops at the end of the header
+		assert_eq!(read_exact_nonblocking(&mut server, 18).unwrap(), b"GET / HTTP/1.1\r\n\r\n");
+
+		let mut blocking = server.into_blocking(None).unwrap();
+		let mut rest = [0u8; 4];
+		blocking.read_exact(&mut rest).unwrap();
+		assert_eq!(&rest, b"more");
+
+		blocking.write_all(b"ok").unwrap();
+		assert_eq!(&client_thd.join().unwrap(), b"ok");
+	}
+}
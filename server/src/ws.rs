@@ -1,25 +1,60 @@
-use std::net::TcpStream;
+use std::io::Write;
+use std::fmt;
+use std::str;
 use base64;
 use sha1;
 use http;
+use deflate::{DeflateParams, Inflater};
 
-pub fn init_websocket_connection(mut stream: &mut TcpStream, header: &http::Request) -> Result<(), String> {
-	if !header.get("Sec-WebSocket-Protocol").unwrap_or("").contains("binary") {
-		let _ = http::Response::new("HTTP/1.1 400 Bad Request")
-			.write_to_stream(&mut stream);
+// Subprotocols the server speaks, in case the client offers more than one.
+// "binary" is what clients sent before the protocol was versioned
+pub const SUBPROTOCOLS: &[&str] = &["wire.v1", "binary"];
 
-		return Err("Invalid websocket protocol".to_string());
+// Returns the permessage-deflate parameters, if they were negotiated.
+// Requests from origins not in allowed_origins are refused, unless it's empty
+pub fn init_websocket_connection<W: Write>(mut stream: &mut W, header: &http::Request, allowed_origins: &[String]) -> Result<Option<DeflateParams>, String> {
+	if header.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+		let reason = "Unsupported websocket version";
+		write_handshake_error(&mut stream, "HTTP/1.1 426 Upgrade Required", &[("Sec-WebSocket-Version", "13")], reason);
+		return Err(reason.to_string());
 	}
 
-	let magic = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 	let key = match header.get("Sec-WebSocket-Key") {
 		Some(k) => k, None => {
-			let _ = http::Response::new("HTTP/1.1 400 Bad Request")
-				.write_to_stream(&mut stream);
+			let reason = "Missing websocket key";
+			write_handshake_error(&mut stream, "HTTP/1.1 400 Bad Request", &[], reason);
+			return Err(reason.to_string());
+		}
+	};
 
-			return Err("Missing websocket key".to_string());
+	// Only browsers send an Origin, and anything else could lie about it anyway
+	if let Some(origin) = header.get("Origin") {
+		if !allowed_origins.is_empty() && !allowed_origins.iter().any(|o| o.eq_ignore_ascii_case(origin)) {
+			let reason = format!("Origin '{}' not allowed", origin);
+			write_handshake_error(&mut stream, "HTTP/1.1 403 Forbidden", &[], &reason);
+			return Err(reason);
+		}
+	}
+
+	// Take the first protocol the client asked for that the server knows.
+	// The list can be split over several headers
+	let protocol = header.get_all("Sec-WebSocket-Protocol").into_iter()
+		.flat_map(|p| p.split(','))
+		.map(str::trim)
+		.filter_map(|p| SUBPROTOCOLS.iter().find(|&&sp| sp == p))
+		.next();
+
+	let protocol = match protocol {
+		// 426 would tell the client to change protocol version, which isn't the problem
+		Some(p) => *p, None => {
+			let reason = "No supported websocket protocol";
+			let supported = SUBPROTOCOLS.join(", ");
+			write_handshake_error(&mut stream, "HTTP/1.1 400 Bad Request", &[("Sec-WebSocket-Protocol", &supported)], reason);
+			return Err(reason.to_string());
 		}
 	};
+
+	let magic = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 	let accept_magic = format!("{}{}", key, magic);
 
 	let mut m = sha1::Sha1::new();
@@ -30,18 +65,38 @@ pub fn init_websocket_connection(mut stream: &mut TcpStream, header: &http::Requ
 	res.set("Upgrade", "websocket");
 	res.set("Connection", "Upgrade");
 	res.set("Sec-WebSocket-Version", "13");
-	res.set("Sec-WebSocket-Protocol", "binary");
+	res.set("Sec-WebSocket-Protocol", protocol);
 	res.set("Sec-WebSocket-Accept", accept_key.as_str());
 
+	let deflate = DeflateParams::negotiate(&header.get_all("Sec-WebSocket-Extensions").join(","));
+
+	let extensions = deflate.map(|d| d.response_header());
+	if let Some(ref extensions) = extensions {
+		res.set("Sec-WebSocket-Extensions", extensions.as_str());
+	}
+
 	res.set("Cache-Control", "no-cache");
 	res.set("Pragma", "no-cache");
 
 	match res.write_to_stream(&mut stream) {
-		Ok(_) => Ok(()),
+		Ok(_) => Ok(deflate),
 		Err(e) => Err(format!("{:?}", e))
 	}
 }
 
+// Refuses an upgrade, with the reason as a plain text body
+pub fn write_handshake_error<W: Write>(mut stream: &mut W, status_line: &str, headers: &[(&str, &str)], reason: &str) {
+	let mut res = http::Response::new(status_line);
+	res.set("Connection", "close");
+	res.set("Content-Type", "text/plain");
+	for &(key, value) in headers {
+		res.set(key, value);
+	}
+	res.set_body(reason.as_bytes());
+
+	let _ = res.write_to_stream(&mut stream);
+}
+
  //  0                   1                   2                   3
  //  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
  // +-+-+-+-+-------+-+-------------+-------------------------------+
@@ -69,66 +124,447 @@ fn test_bit(v: u16, bit: u8) -> bool {
 	(v & 1<<bit) != 0
 }
 
-pub fn decode_ws_packet<'a>(buf: &'a mut [u8]) -> &'a [u8] {
-	let header = (buf[0] as u16) << 8 | buf[1] as u16;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_NO_STATUS: u16 = 1005; // Never sent, reported when a close frame has no status
+pub const CLOSE_ABNORMAL: u16 = 1006; // Never sent, reported when the connection drops without a close frame
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+pub const MAX_CONTROL_PAYLOAD_LEN: usize = 125;
+
+pub struct Frame {
+	pub final_frame: bool,
+	pub rsv: u8,
+	pub opcode: u8,
+	pub masked: bool,
+	pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CloseReason {
+	pub status: u16,
+	pub reason: String,
+}
+
+impl CloseReason {
+	pub fn new(status: u16, reason: &str) -> Self {
+		CloseReason {
+			status,
+			reason: reason.to_string(),
+		}
+	}
+
+	// Parses the body of a close frame
+	fn parse(payload: &[u8]) -> Result<CloseReason, FrameError> {
+		match payload.len() {
+			0 => Ok(CloseReason::new(CLOSE_NO_STATUS, "")),
+			1 => Err(FrameError::TruncatedCloseStatus),
+			_ => {
+				let status = (payload[0] as u16) << 8 | payload[1] as u16;
+				if !is_valid_close_status(status) {
+					return Err(FrameError::InvalidCloseStatus(status));
+				}
+
+				match str::from_utf8(&payload[2..]) {
+					Ok(reason) => Ok(CloseReason::new(status, reason)),
+					Err(_) => Err(FrameError::InvalidUtf8),
+				}
+			}
+		}
+	}
+}
+
+impl fmt::Display for CloseReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.reason.is_empty() {
+			write!(f, "{}", self.status)
+		} else {
+			write!(f, "{} ({})", self.status, self.reason)
+		}
+	}
+}
+
+// Whether status is allowed to appear in a close frame
+fn is_valid_close_status(status: u16) -> bool {
+	match status {
+		1004 | CLOSE_NO_STATUS | CLOSE_ABNORMAL => false,
+		1000...1014 => true,
+		3000...4999 => true,
+		_ => false,
+	}
+}
+
+// Ways a client can break the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+	ReservedOpcode(u8),
+	ReservedBits(u8),
+	Unmasked,
+	InvalidLength,
+	FragmentedControlFrame,
+	ControlFrameTooLong,
+	UnexpectedContinuation,
+	UnfinishedMessage,
+	MessageTooBig,
+	InvalidUtf8,
+	InvalidCloseStatus(u16),
+	TruncatedCloseStatus,
+	InvalidCompressedData,
+}
+
+impl FrameError {
+	// The status the connection should be closed with
+	pub fn close_status(&self) -> u16 {
+		match *self {
+			FrameError::MessageTooBig => CLOSE_MESSAGE_TOO_BIG,
+			FrameError::InvalidUtf8 | FrameError::InvalidCompressedData => CLOSE_INVALID_PAYLOAD,
+			_ => CLOSE_PROTOCOL_ERROR,
+		}
+	}
+}
+
+impl fmt::Display for FrameError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			FrameError::ReservedOpcode(op) => write!(f, "Reserved opcode {:#x}", op),
+			FrameError::ReservedBits(rsv) => write!(f, "Unexpected reserved bits {:#05b}", rsv),
+			FrameError::Unmasked => write!(f, "Unmasked frame"),
+			FrameError::InvalidLength => write!(f, "Invalid payload length"),
+			FrameError::FragmentedControlFrame => write!(f, "Fragmented control frame"),
+			FrameError::ControlFrameTooLong => write!(f, "Control frame too long"),
+			FrameError::UnexpectedContinuation => write!(f, "Continuation without a message"),
+			FrameError::UnfinishedMessage => write!(f, "New message before the last was finished"),
+			FrameError::MessageTooBig => write!(f, "Message too big"),
+			FrameError::InvalidUtf8 => write!(f, "Invalid utf8 in text"),
+			FrameError::InvalidCloseStatus(status) => write!(f, "Invalid close status {}", status),
+			FrameError::TruncatedCloseStatus => write!(f, "Truncated close status"),
+			FrameError::InvalidCompressedData => write!(f, "Invalid compressed data"),
+		}
+	}
+}
+
+pub enum Message {
+	Binary(Vec<u8>),
+	Text(String),
+	Close(CloseReason),
+	Ping(Vec<u8>),
+	Pong(Vec<u8>),
+}
+
+// Accumulates bytes read from a stream and splits them into messages.
+// A single read may contain a partial frame, several frames, or both, so
+// anything left over after the last complete frame is kept for the next read.
+// Fragmented messages are reassembled before being returned
+pub struct FrameDecoder {
+	buffer: Vec<u8>,
+	fragments: Option<Vec<u8>>,
+	max_message_size: usize,
+
+	// Text is validated as fragments arrive, so bad data can be rejected early.
+	// utf8_checked is how much of the fragments so far is known to be valid
+	message_text: bool,
+	utf8_checked: usize,
+
+	// Only present if permessage-deflate was negotiated
+	inflater: Option<Inflater>,
+	message_compressed: bool,
+}
+
+impl FrameDecoder {
+	pub fn new(max_message_size: usize, inflater: Option<Inflater>) -> Self {
+		FrameDecoder {
+			buffer: Vec::new(),
+			fragments: None,
+			max_message_size,
+
+			message_text: false,
+			utf8_checked: 0,
+
+			inflater,
+			message_compressed: false,
+		}
+	}
+
+	pub fn push_bytes(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+	}
+
+	// Returns the next complete message, or how the client broke the protocol
+	pub fn next_message(&mut self) -> Result<Option<Message>, FrameError> {
+		while let Some(frame) = self.next_frame()? {
+			// Clients have to mask everything they send
+			if !frame.masked {
+				return Err(FrameError::Unmasked);
+			}
+
+			// RSV1 marks the first frame of a compressed message, the others aren't used by any extension
+			let rsv1 = frame.rsv & 0b100 != 0;
+			if frame.rsv & 0b011 != 0 {
+				return Err(FrameError::ReservedBits(frame.rsv));
+			}
+
+			if rsv1 && (self.inflater.is_none() || (frame.opcode != 0x1 && frame.opcode != 0x2)) {
+				return Err(FrameError::ReservedBits(frame.rsv));
+			}
+
+			match frame.opcode {
+				0x0 => {
+					let mut message = match self.fragments.take() {
+						Some(m) => m,
+						None => return Err(FrameError::UnexpectedContinuation)
+					};
+
+					if message.len() + frame.payload.len() > self.max_message_size {
+						return Err(FrameError::MessageTooBig);
+					}
+
+					message.extend_from_slice(&frame.payload);
+					self.check_utf8(&message)?;
+
+					if frame.final_frame {
+						return self.finish_message(message).map(Some);
+					}
+
+					self.fragments = Some(message);
+				}
+
+				0x1 | 0x2 => {
+					// A new message can't start until the previous one is finished
+					if self.fragments.is_some() {
+						return Err(FrameError::UnfinishedMessage);
+					}
 
-	let final_packet = test_bit(header, 0);
-	let opcode = extract_bits(header, 4, 4);
-	let masked = test_bit(header, 8); // Client packets should always be masked
-	let len = extract_bits(header, 9, 7) as usize;
+					if frame.payload.len() > self.max_message_size {
+						return Err(FrameError::MessageTooBig);
+					}
 
-	// TODO: handle continuation
-	assert!(final_packet);
+					self.message_compressed = rsv1;
+					self.message_text = frame.opcode == 0x1;
+					self.utf8_checked = 0;
+
+					if frame.final_frame {
+						return self.finish_message(frame.payload).map(Some);
+					}
+
+					self.check_utf8(&frame.payload)?;
+					self.fragments = Some(frame.payload);
+				}
+
+				// Control frames can be interleaved with fragments, but can't be fragmented themselves
+				0x8 | 0x9 | 0xA => {
+					if !frame.final_frame {
+						return Err(FrameError::FragmentedControlFrame);
+					}
+
+					if frame.payload.len() > MAX_CONTROL_PAYLOAD_LEN {
+						return Err(FrameError::ControlFrameTooLong);
+					}
+
+					return Ok(Some(match frame.opcode {
+						0x8 => Message::Close(CloseReason::parse(&frame.payload)?),
+						0x9 => Message::Ping(frame.payload),
+						_ => Message::Pong(frame.payload),
+					}));
+				}
+
+				// Already caught by decode_frame_header
+				opcode => return Err(FrameError::ReservedOpcode(opcode))
+			}
+		}
+
+		// Don't wait around for the rest of a frame that's going to be rejected anyway
+		if let Some(header) = decode_frame_header(&self.buffer)? {
+			if header.payload_len > self.max_message_size as u64 {
+				return Err(FrameError::MessageTooBig);
+			}
+		}
+
+		Ok(None)
+	}
+
+	fn finish_message(&mut self, payload: Vec<u8>) -> Result<Message, FrameError> {
+		let payload = if self.message_compressed {
+			match self.inflater {
+				Some(ref mut inflater) => inflater.decompress_message(&payload, self.max_message_size)?,
+				None => return Err(FrameError::ReservedBits(0b100)),
+			}
+		} else {
+			payload
+		};
+
+		if !self.message_text {
+			return Ok(Message::Binary(payload));
+		}
+
+		// This also catches a character cut off by the end of the message
+		String::from_utf8(payload)
+			.map(Message::Text)
+			.map_err(|_| FrameError::InvalidUtf8)
+	}
+
+	// Fails as soon as a partial text message can't be valid. A character split between
+	// fragments is left unchecked until the next one arrives.
+	// Compressed text can only be checked once it's been inflated
+	fn check_utf8(&mut self, message: &[u8]) -> Result<(), FrameError> {
+		if !self.message_text || self.message_compressed {
+			return Ok(());
+		}
+
+		match str::from_utf8(&message[self.utf8_checked..]) {
+			Ok(_) => self.utf8_checked = message.len(),
+			Err(ref e) if e.error_len().is_none() => self.utf8_checked += e.valid_up_to(),
+			Err(_) => return Err(FrameError::InvalidUtf8),
+		}
+
+		Ok(())
+	}
+
+	fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+		let (frame, frame_len) = match decode_ws_packet(&self.buffer)? {
+			Some(f) => f, None => return Ok(None)
+		};
+
+		self.buffer.drain(..frame_len);
+		Ok(Some(frame))
+	}
+}
+
+pub struct FrameHeader {
+	pub final_frame: bool,
+	pub rsv: u8,
+	pub opcode: u8,
+	pub masked: bool,
+	pub payload_len: u64,
+	pub header_len: usize, // including the mask
+}
+
+impl FrameHeader {
+	// Saturates rather than overflowing for absurd lengths, which can't fit in a buffer anyway
+	pub fn frame_len(&self) -> u64 {
+		self.payload_len.saturating_add(self.header_len as u64)
+	}
+}
+
+// Decodes the header of the frame at the start of buf, without waiting for the payload.
+// Returns None if buf doesn't yet contain the entire header
+pub fn decode_frame_header(buf: &[u8]) -> Result<Option<FrameHeader>, FrameError> {
+	if buf.len() < 2 { return Ok(None) }
+
+	let header = (buf[0] as u16) << 8 | buf[1] as u16;
+
+	let final_frame = test_bit(header, 0);
+	let rsv = extract_bits(header, 1, 3) as u8;
+	let opcode = extract_bits(header, 4, 4) as u8;
+	let masked = test_bit(header, 8);
+	let len = extract_bits(header, 9, 7) as u64;
 
 	match opcode {
-		0x0 => panic!("Continuation frames not implemented"),
-		0x1 => panic!("Text frames not implemented"), // Emscripten doesn't do text frames so this is fine
-		0x2 => {},
-		0x3...0x7 => panic!("Reserved opcode {}", opcode),
-		0x8 => {
-			return &buf[0..0];
-		},
-		0x9 => panic!("Ping frame not handled"),
-		0xA => panic!("Pong frame not handled"),
-		0xB...0xF => panic!("Reserved control frame {}", opcode),
-		_ => unreachable!()
+		0x3...0x7 | 0xB...0xF => return Err(FrameError::ReservedOpcode(opcode)),
+		_ => {}
 	}
 
-	assert!(buf.len() > 2);
+	let (payload_len, len_size) = match len {
+		127 => {
+			if buf.len() < 10 { return Ok(None) }
+			let extlen = buf[2..10].iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+
+			// The top bit is reserved, and has to be clear
+			if extlen >> 63 != 0 {
+				return Err(FrameError::InvalidLength);
+			}
 
-	let extlen = match len {
-		127 => unimplemented!(),
-		126 => (buf[2] as usize) << 8 | buf[3] as usize,
-		_ => len
+			(extlen, 8)
+		},
+		126 => {
+			if buf.len() < 4 { return Ok(None) }
+			((buf[2] as u64) << 8 | buf[3] as u64, 2)
+		},
+		_ => (len, 0)
 	};
 
-	let mut payload = match len {
-		127 => &mut buf[10..],
-		126 => &mut buf[4..],
-		_ => &mut buf[2..],
+	let mask_len = if masked { 4 } else { 0 };
+
+	Ok(Some(FrameHeader {
+		final_frame,
+		rsv,
+		opcode,
+		masked,
+		payload_len,
+		header_len: 2 + len_size + mask_len,
+	}))
+}
+
+// Decodes the frame at the start of buf, returning it along with the number of bytes it took up.
+// Returns None if buf doesn't yet contain an entire frame
+pub fn decode_ws_packet(buf: &[u8]) -> Result<Option<(Frame, usize)>, FrameError> {
+	let header = match decode_frame_header(buf)? {
+		Some(h) => h, None => return Ok(None)
 	};
 
-	let expected_len = if masked { extlen+4 } else { extlen };
-	if payload.len() < expected_len {
-		println!("Payload length doesn't match packet ({} != {})", payload.len(), expected_len);
-		return &payload[0..0];
+	if (buf.len() as u64) < header.frame_len() {
+		return Ok(None);
 	}
 
-	if masked {
-		let mut mask = [0u8; 4];
-		mask.clone_from_slice(&payload[..4]);
+	let frame_len = header.frame_len() as usize;
+	let mut payload = buf[header.header_len .. frame_len].to_vec();
 
-		for (i, val) in payload[4..expected_len].iter_mut().enumerate() {
+	if header.masked {
+		let mask = &buf[header.header_len - 4 .. header.header_len];
+
+		for (i, val) in payload.iter_mut().enumerate() {
 			*val ^= mask[i % mask.len()];
 		}
+	}
+
+	let frame = Frame {
+		final_frame: header.final_frame,
+		rsv: header.rsv,
+		opcode: header.opcode,
+		masked: header.masked,
+		payload,
+	};
+
+	Ok(Some((frame, frame_len)))
+}
 
-		&payload[4..expected_len]
-	} else {
-		&payload[..expected_len]
+pub fn encode_ws_packet(buf: &mut Vec<u8>, payload: &[u8]) {
+	encode_ws_frame(buf, 0x2, payload)
+}
+
+// Payload must already have been compressed with the connections deflate context
+pub fn encode_compressed_ws_packet(buf: &mut Vec<u8>, payload: &[u8]) {
+	encode_frame(buf, 0x2, true, payload)
+}
+
+// Reasons that don't fit in a control frame are truncated
+pub fn encode_close_frame(buf: &mut Vec<u8>, status: u16, reason: &str) {
+	if status == CLOSE_NO_STATUS {
+		encode_ws_frame(buf, 0x8, &[]);
+		return;
 	}
+
+	let mut reason_len = reason.len().min(MAX_CONTROL_PAYLOAD_LEN - 2);
+	while !reason.is_char_boundary(reason_len) {
+		reason_len -= 1;
+	}
+
+	let mut payload = [0u8; MAX_CONTROL_PAYLOAD_LEN];
+	payload[0] = (status >> 8) as u8;
+	payload[1] = (status & 0xFF) as u8;
+	payload[2..2+reason_len].copy_from_slice(&reason.as_bytes()[..reason_len]);
+
+	encode_ws_frame(buf, 0x8, &payload[..2+reason_len])
 }
 
-pub fn encode_ws_packet<'a>(buf: &'a mut [u8], payload: &[u8]) -> &'a [u8] {
+// Appends an entire frame to buf
+pub fn encode_ws_frame(buf: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+	encode_frame(buf, opcode, false, payload)
+}
+
+fn encode_frame(buf: &mut Vec<u8>, opcode: u8, compressed: bool, payload: &[u8]) {
 	let short_len = match payload.len() {
 		l @ 0...125 => l,
 		126...65535 => 126,
@@ -138,39 +574,28 @@ pub fn encode_ws_packet<'a>(buf: &'a mut [u8], payload: &[u8]) -> &'a [u8] {
 	// Compile header
 	let mut header = 0u16;
 	header |= 1 << 15; // FIN
-	header |= 0x2 << 8; // opcode
+	if compressed { header |= 1 << 14; } // RSV1
+	header |= (opcode as u16 & 0xF) << 8; // opcode
 	header |= short_len as u16 & ((1<<7) - 1); // len field
 
-	buf[0] = (header >> 8) as u8;
-	buf[1] = (header & 0xFF) as u8;
+	buf.push((header >> 8) as u8);
+	buf.push((header & 0xFF) as u8);
 
-	let len = payload.len();
+	let len = payload.len() as u64;
 
 	// Write payload length
 	match short_len {
-		127 => unimplemented!(), // 64b length
+		127 => {
+			for i in (0..8).rev() {
+				buf.push((len >> (i*8) & 0xFF) as u8);
+			}
+		},
 		126 => {
-			buf[2] = (len >> 8) as u8;
-			buf[3] = (len & 0xFF) as u8;
+			buf.push((len >> 8) as u8);
+			buf.push((len & 0xFF) as u8);
 		},
 		_ => {},
 	}
 
-	// Copy payload
-	{
-		let mut payload_dst = match short_len {
-			127 => &mut buf[10..],
-			126 => &mut buf[4..],
-			_ => &mut buf[2..],
-		};
-
-		payload_dst[..len].copy_from_slice(&payload[..]);
-	}
-
-	// Return slice containing the entire packet
-	match short_len {
-		127 => &buf[.. 10 + len],
-		126 => &buf[.. 4 + len],
-		_ => &buf[.. 2 + len]
-	}
+	buf.extend_from_slice(payload);
 }